@@ -0,0 +1,101 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::{self, BufRead, Read};
+use async_std::stream::Stream;
+
+/// How many chunks producer may get ahead of the client.
+const CAPACITY: usize = 64;
+
+/// Creates response body which is fed with chunks sent through returned sender,
+/// so that page can be sent to browser while it is still being rendered.
+/// Body ends once all senders are dropped.
+pub fn channel() -> (Sender<String>, ChannelReader) {
+    let (tx, rx) = channel::bounded(CAPACITY);
+    (
+        tx,
+        ChannelReader {
+            chunks: rx,
+            chunk: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+/// Formats error shown in place of page content, escaping its message.
+pub fn error(err: &impl std::fmt::Display) -> String {
+    format!(
+        "<pre>error loading resource: {} </pre>",
+        html_escape::encode_text(&err.to_string())
+    )
+}
+
+pub struct ChannelReader {
+    chunks: Receiver<String>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl BufRead for ChannelReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.pos == this.chunk.len() {
+            match ready!(Pin::new(&mut this.chunks).poll_next(cx)) {
+                Some(chunk) => {
+                    this.chunk = chunk.into_bytes();
+                    this.pos = 0;
+                }
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(&this.chunk[this.pos..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().pos += amt;
+    }
+}
+
+impl Read for ChannelReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::ReadExt;
+    use async_std::task;
+
+    #[async_std::test]
+    async fn streaming() {
+        let (tx, mut reader) = channel();
+        task::spawn(async move {
+            for chunk in ["<table>", "", "<tr></tr>", "</table>"] {
+                tx.send(String::from(chunk)).await.unwrap();
+            }
+        });
+        let mut body = String::new();
+        reader.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, "<table><tr></tr></table>");
+    }
+
+    #[test]
+    fn error_escaped() {
+        let err = anyhow::anyhow!("<script>alert(1)</script>");
+        assert_eq!(
+            error(&err),
+            "<pre>error loading resource: &lt;script&gt;alert(1)&lt;/script&gt; </pre>"
+        );
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_std::io::{BufRead, BufReader, Cursor, Read, ReadExt};
+use async_std::task;
+use tide::log;
+
+use crate::gopher::{self, GopherURL};
+use crate::limits::FetchOptions;
+use crate::metrics::METRICS;
+use crate::store::DiskStore;
+use crate::tls::{self, TlsConfig};
+
+/// Boxed response body, either served from cache or streamed from upstream.
+pub type Response = Box<dyn BufRead + Unpin + Send + Sync>;
+
+/// Response body along with its age.
+/// Body is either read completely or streamed, see [`Cache::fetch_stream`].
+pub struct Fetched<T = Arc<[u8]>> {
+    pub data: T,
+    pub age: Duration,
+    /// Response is past its TTL and is being refreshed in background.
+    pub refreshing: bool,
+    /// Primary server was unreachable and response came from this mirror.
+    pub mirror: Option<GopherURL>,
+}
+
+/// Returned in offline mode for resources that were never fetched.
+#[derive(Debug)]
+pub struct NotCached(pub String);
+
+impl std::fmt::Display for NotCached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not cached", self.0)
+    }
+}
+
+impl std::error::Error for NotCached {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub url: GopherURL,
+    pub query: Option<String>,
+}
+
+impl CacheKey {
+    pub fn new(url: &GopherURL, query: Option<&str>) -> Self {
+        // the same item is cached regardless of mirrors it was found with
+        Self {
+            url: GopherURL {
+                mirrors: Vec::new(),
+                ..url.clone()
+            },
+            query: query.map(String::from),
+        }
+    }
+}
+
+struct Entry {
+    data: Arc<[u8]>,
+    fetched: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<CacheKey, Entry>,
+    // recency index, the smallest tick is the least recently used entry
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(e) = self.entries.remove(key) {
+            self.lru.remove(&e.tick);
+            self.size -= e.data.len();
+        }
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        if let Some(e) = self.entries.get_mut(key) {
+            self.lru.remove(&e.tick);
+            e.tick = self.tick;
+            self.lru.insert(self.tick, key.clone());
+        }
+    }
+}
+
+/// Bounded in-memory LRU cache of gopher responses.
+///
+/// Entries become stale after `ttl`, but are kept until evicted, so they can be served
+/// while being revalidated. Total size of cached bodies never exceeds `max_bytes`.
+/// Single response bigger than a quarter of the budget is not cached at all,
+/// so one large download won't flush the whole cache.
+///
+/// Optionally backed by [`DiskStore`], which keeps responses across restarts
+/// and allows browsing already visited resources in offline mode.
+pub struct Cache {
+    inner: Mutex<Inner>,
+    ttl: Duration,
+    max_bytes: usize,
+    store: Option<Arc<DiskStore>>,
+    tls: Arc<TlsConfig>,
+    options: RwLock<FetchOptions>,
+    offline: AtomicBool,
+    refreshing: Mutex<HashSet<CacheKey>>,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, max_bytes: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            ttl,
+            max_bytes,
+            store: None,
+            tls: Arc::new(TlsConfig::default()),
+            options: RwLock::default(),
+            offline: AtomicBool::new(false),
+            refreshing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn with_store(mut self, store: DiskStore) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// TLS settings used to connect to upstream servers.
+    pub fn with_tls(mut self, tls: Arc<TlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Limits applied to fetching from upstream servers.
+    pub fn with_options(self, options: FetchOptions) -> Self {
+        self.set_options(options);
+        self
+    }
+
+    pub fn options(&self) -> FetchOptions {
+        self.options.read().unwrap().clone()
+    }
+
+    /// Changes limits for fetches started from now on.
+    pub fn set_options(&self, options: FetchOptions) {
+        *self.options.write().unwrap() = options;
+    }
+
+    /// In offline mode nothing is fetched from upstream, and stored responses never expire.
+    pub fn offline(self, offline: bool) -> Self {
+        self.set_offline(offline);
+        self
+    }
+
+    /// Switches offline mode for requests made from now on.
+    /// Cache without store has nothing to serve offline, so it stays online.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline
+            .store(offline && self.store.is_some(), Ordering::Relaxed);
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    fn max_entry_size(&self) -> usize {
+        self.max_bytes / 4
+    }
+
+    /// Returns fresh response, if there is one.
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        match self.peek(key) {
+            Some((data, age)) if age < self.ttl => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Returns response regardless of its age.
+    fn peek(&self, key: &CacheKey) -> Option<(Arc<[u8]>, Duration)> {
+        let mut inner = self.inner.lock().unwrap();
+        let found = inner
+            .entries
+            .get(key)
+            .map(|e| (e.data.clone(), e.fetched.elapsed()))?;
+        inner.touch(key);
+        Some(found)
+    }
+
+    pub fn insert(&self, key: CacheKey, data: impl Into<Arc<[u8]>>) {
+        self.insert_with_age(key, data.into(), Duration::ZERO)
+    }
+
+    fn insert_with_age(&self, key: CacheKey, data: Arc<[u8]>, age: Duration) {
+        if data.len() > self.max_entry_size() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.size + data.len() > self.max_bytes {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.size += data.len();
+        inner.lru.insert(tick, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                data,
+                fetched: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                tick,
+            },
+        );
+    }
+
+    /// Puts response into cache and, if configured, into disk store.
+    pub fn store(&self, key: CacheKey, data: impl Into<Arc<[u8]>>) {
+        let data = data.into();
+        self.insert(key.clone(), data.clone());
+        if let Some(store) = self.store.clone() {
+            task::spawn_blocking(move || {
+                if let Err(e) = store.put(&key, &data) {
+                    log::error!("failed to store {}: {}", key.url, e);
+                }
+            });
+        }
+    }
+
+    /// Looks up response in memory, then on disk.
+    async fn lookup(&self, key: &CacheKey, allow_stale: bool) -> Option<(Arc<[u8]>, Duration)> {
+        let usable = |age: Duration| age < self.ttl || allow_stale || self.is_offline();
+        if let Some((data, age)) = self.peek(key) {
+            if usable(age) {
+                log::debug!("cache hit for {}", key.url);
+                return Some((data, age));
+            }
+        }
+        let store = self.store.clone()?;
+        let k = key.clone();
+        let (data, fetched) = match task::spawn_blocking(move || store.get(&k)).await {
+            Ok(Some(found)) => found,
+            Ok(None) => return None,
+            Err(e) => {
+                log::error!("failed to read {} from store: {}", key.url, e);
+                return None;
+            }
+        };
+        let age = fetched.elapsed().unwrap_or_default();
+        if !usable(age) {
+            return None;
+        }
+        log::debug!("store hit for {}", key.url);
+        let data: Arc<[u8]> = data.into();
+        self.insert_with_age(key.clone(), data.clone(), age);
+        Some((data, age))
+    }
+
+    /// Returns whole response body, fetching it from upstream if it's not cached.
+    /// Stale response is returned right away and refreshed in background.
+    /// With `refresh` set, response is always fetched from upstream, unless in offline mode.
+    pub async fn fetch(
+        self: &Arc<Self>,
+        url: &GopherURL,
+        query: Option<String>,
+        refresh: bool,
+    ) -> anyhow::Result<Fetched> {
+        let key = CacheKey::new(url, query.as_deref());
+        if let Some(cached) = self.cached(key, refresh).await {
+            return Ok(cached);
+        }
+        if self.is_offline() {
+            return Err(NotCached(url.to_string()).into());
+        }
+        let (data, mirror) = self.refetch(url, query).await?;
+        Ok(Fetched {
+            data,
+            age: Duration::ZERO,
+            refreshing: false,
+            mirror,
+        })
+    }
+
+    /// Looks up cached response for `fetch`, scheduling refresh of stale one.
+    async fn cached(self: &Arc<Self>, key: CacheKey, refresh: bool) -> Option<Fetched> {
+        if refresh && !self.is_offline() {
+            return None;
+        }
+        let Some((data, age)) = self.lookup(&key, true).await else {
+            METRICS.cache.inc(&["miss"]);
+            return None;
+        };
+        let refreshing = age >= self.ttl && !self.is_offline();
+        if refreshing {
+            METRICS.cache.inc(&["stale"]);
+            self.refresh_in_background(key);
+        } else {
+            METRICS.cache.inc(&["hit"]);
+        }
+        Some(Fetched {
+            data,
+            age,
+            refreshing,
+            mirror: None,
+        })
+    }
+
+    fn refresh_in_background(self: &Arc<Self>, key: CacheKey) {
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            // already in progress
+            return;
+        }
+        let cache = self.clone();
+        task::spawn(async move {
+            log::debug!("refreshing {}", key.url);
+            if let Err(e) = cache.refetch(&key.url, key.query.clone()).await {
+                log::warn!("failed to refresh {}: {}", key.url, e);
+            }
+            cache.refreshing.lock().unwrap().remove(&key);
+        });
+    }
+
+    /// Fetches response from upstream bypassing cache, and caches it.
+    async fn refetch(
+        &self,
+        url: &GopherURL,
+        query: Option<String>,
+    ) -> anyhow::Result<(Arc<[u8]>, Option<GopherURL>)> {
+        let key = CacheKey::new(url, query.as_deref());
+        let mut data = Vec::new();
+        let (mut response, mirror) =
+            gopher::fetch_mirrored(url, query, &self.tls, &self.options()).await?;
+        response.read_to_end(&mut data).await?;
+        let data: Arc<[u8]> = data.into();
+        self.store(key, data.clone());
+        Ok((data, mirror))
+    }
+
+    /// Same as `fetch`, but streams response from upstream on cache miss,
+    /// storing it into cache once it is read till the end.
+    pub async fn fetch_stream(
+        self: &Arc<Self>,
+        url: &GopherURL,
+        query: Option<String>,
+        refresh: bool,
+    ) -> anyhow::Result<Fetched<Response>> {
+        let key = CacheKey::new(url, query.as_deref());
+        if let Some(cached) = self.cached(key.clone(), refresh).await {
+            return Ok(Fetched {
+                data: Box::new(Cursor::new(cached.data)),
+                age: cached.age,
+                refreshing: cached.refreshing,
+                mirror: None,
+            });
+        }
+        if self.is_offline() {
+            return Err(NotCached(url.to_string()).into());
+        }
+        let (upstream, mirror) =
+            gopher::fetch_mirrored(url, query, &self.tls, &self.options()).await?;
+        Ok(Fetched {
+            data: Box::new(BufReader::new(CachingReader {
+                inner: upstream,
+                buf: Some(Vec::new()),
+                key,
+                cache: self.clone(),
+            })),
+            age: Duration::ZERO,
+            refreshing: false,
+            mirror,
+        })
+    }
+}
+
+/// Passes data through, keeping a copy to be put into cache on EOF.
+struct CachingReader<R> {
+    inner: R,
+    buf: Option<Vec<u8>>,
+    key: CacheKey,
+    cache: Arc<Cache>,
+}
+
+impl<R: Read + Unpin> Read for CachingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let n = match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            // response may be incomplete, so it is passed on without being cached
+            Poll::Ready(Err(e)) if tls::is_truncated(&e) => {
+                log::warn!("not caching {:?}: {}", this.key, e);
+                this.buf = None;
+                return Poll::Ready(Ok(0));
+            }
+            other => return other,
+        };
+        if n == 0 {
+            if let Some(data) = this.buf.take() {
+                this.cache.store(this.key.clone(), data);
+            }
+        } else if let Some(data) = this.buf.as_mut() {
+            if data.len() + n > this.cache.max_entry_size() {
+                this.buf = None;
+            } else {
+                data.extend_from_slice(&buf[..n]);
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(selector: &str) -> CacheKey {
+        let url = GopherURL::try_from(format!("example.com/0{}", selector).as_str()).unwrap();
+        CacheKey::new(&url, None)
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = Cache::new(Duration::from_secs(60), 40);
+        cache.insert(key("/a"), vec![0; 10]);
+        cache.insert(key("/b"), vec![0; 10]);
+        cache.insert(key("/c"), vec![0; 10]);
+        assert!(cache.get(&key("/a")).is_some());
+        // budget is exhausted, least recently used "/b" has to go
+        cache.insert(key("/d"), vec![0; 10]);
+        cache.insert(key("/e"), vec![0; 10]);
+        assert!(cache.get(&key("/a")).is_some());
+        assert!(cache.get(&key("/b")).is_none());
+        assert!(cache.get(&key("/e")).is_some());
+        // too big to be cached
+        cache.insert(key("/f"), vec![0; 11]);
+        assert!(cache.get(&key("/f")).is_none());
+    }
+
+    #[test]
+    fn ttl_expiration() {
+        let cache = Cache::new(Duration::ZERO, 100);
+        cache.insert(key("/a"), vec![0; 10]);
+        assert!(cache.get(&key("/a")).is_none());
+        // stale entry is still there to be revalidated
+        let (_, age) = cache.peek(&key("/a")).unwrap();
+        assert!(age >= cache.ttl);
+        assert_eq!(cache.inner.lock().unwrap().size, 10);
+    }
+
+    /// Reader failing the way TLS connection closed without close_notify does.
+    struct Truncated;
+
+    impl Read for Truncated {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                tls::Truncated,
+            )))
+        }
+    }
+
+    #[test]
+    fn truncated_not_cached() {
+        let cache = Arc::new(Cache::new(Duration::from_secs(60), 400));
+        let read = |inner| {
+            let mut reader = CachingReader {
+                inner,
+                buf: Some(Vec::new()),
+                key: key("/a"),
+                cache: cache.clone(),
+            };
+            let mut data = Vec::new();
+            task::block_on(reader.read_to_end(&mut data)).unwrap();
+            data
+        };
+        // client still gets what was received
+        assert_eq!(
+            read(Cursor::new(b"data".to_vec()).chain(Truncated)),
+            b"data"
+        );
+        assert!(cache.get(&key("/a")).is_none());
+    }
+
+    #[async_std::test]
+    async fn offline_reload() {
+        let dir = std::env::temp_dir().join(format!("proxy70-offline-{}", std::process::id()));
+        let cache = Cache::new(Duration::ZERO, 100)
+            .with_store(DiskStore::open(&dir, 1000).unwrap())
+            .offline(true);
+        cache.insert(key("/a"), vec![0; 10]);
+        // stale response is served as long as cache is offline
+        assert!(cache.lookup(&key("/a"), false).await.is_some());
+        cache.set_offline(false);
+        assert!(!cache.is_offline());
+        assert!(cache.lookup(&key("/a"), false).await.is_none());
+        // nothing to serve offline without store
+        assert!(!Cache::new(Duration::ZERO, 100).offline(true).is_offline());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+/// Link shown on start page.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Link {
+    pub title: String,
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Configuration file. It sets command-line flags under their long names,
+/// e.g. `cache-ttl = 600` or `deny-port = [25, 6379]`, and lists start page links
+/// in `[[link]]` tables.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    flags: Vec<Flag>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Flag {
+    name: String,
+    values: Vec<String>,
+    /// Line of configuration file flag is set on
+    line: usize,
+}
+
+impl Config {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        let mut table: Table = text.parse()?;
+        let links = match table.remove("link") {
+            Some(links) => Some(links.try_into()?),
+            None => None,
+        };
+        let mut flags = Vec::new();
+        for (key, value) in table {
+            let values = match value {
+                // boolean flags are given explicitly, so that file can turn them off as well
+                Value::Boolean(b) => vec![b.to_string()],
+                Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| scalar(&key, item))
+                    .collect::<Result<_, _>>()?,
+                value => vec![scalar(&key, value)?],
+            };
+            flags.push(Flag {
+                line: line_of(text, &key).unwrap_or_default(),
+                name: key.replace('_', "-"),
+                values,
+            });
+        }
+        Ok(Self { flags, links })
+    }
+
+    /// Makes sure that all flags set are ones `known` returns true for.
+    pub fn check(&self, known: impl Fn(&str) -> bool) -> Result<(), anyhow::Error> {
+        match self.flags.iter().find(|flag| !known(&flag.name)) {
+            Some(flag) => Err(anyhow!("line {}: unknown flag {}", flag.line, flag.name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Turns configuration into command-line arguments,
+    /// leaving out flags `skip` returns true for.
+    pub fn args(&self, skip: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut args = Vec::new();
+        for flag in self.flags.iter().filter(|flag| !skip(&flag.name)) {
+            if flag.values.is_empty() {
+                args.push(format!("--{}", flag.name));
+            }
+            for value in &flag.values {
+                args.push(format!("--{}={}", flag.name, value));
+            }
+        }
+        args
+    }
+}
+
+/// Number of line setting top-level `key`, which comes before any table.
+fn line_of(text: &str, key: &str) -> Option<usize> {
+    let quoted = format!("\"{}\"", key);
+    text.lines()
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .position(|line| {
+            let line = line.trim_start();
+            line.strip_prefix(key)
+                .or_else(|| line.strip_prefix(&quoted))
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map(|n| n + 1)
+}
+
+fn scalar(key: &str, value: Value) -> Result<String, anyhow::Error> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        _ => Err(anyhow!("unexpected value of {}: {}", key, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let config = Config::parse(
+            r#"
+            listen = ["0.0.0.0:8080", "unix:/run/proxy70.sock"]
+            cache_ttl = 600
+            rate-limit = 0.5
+            opportunistic-tls = true
+            offline = false
+            deny-port = [25, 6379]
+
+            [[link]]
+            title = "Floodgap"
+            url = "gopher://gopher.floodgap.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.args(|flag| flag == "rate-limit"),
+            [
+                "--cache-ttl=600",
+                "--deny-port=25",
+                "--deny-port=6379",
+                "--listen=0.0.0.0:8080",
+                "--listen=unix:/run/proxy70.sock",
+                "--offline=false",
+                "--opportunistic-tls=true",
+            ]
+        );
+        assert_eq!(
+            config.links,
+            Some(vec![Link {
+                title: String::from("Floodgap"),
+                url: String::from("gopher://gopher.floodgap.com"),
+                description: String::new(),
+            }])
+        );
+
+        let known = [
+            "listen",
+            "cache-ttl",
+            "rate-limit",
+            "opportunistic-tls",
+            "offline",
+            "deny-port",
+        ];
+        assert!(config.check(|flag| known.contains(&flag)).is_ok());
+        let config = Config::parse("cache-ttl = 600\n\nlisten-adr = \"[::]:8080\"").unwrap();
+        assert_eq!(
+            config
+                .check(|flag| known.contains(&flag))
+                .unwrap_err()
+                .to_string(),
+            "line 3: unknown flag listen-adr"
+        );
+
+        assert!(Config::parse("[cache]\nttl = 600").is_err());
+    }
+
+    #[test]
+    fn booleans() {
+        // file reloaded with flag turned off overrides it being on before
+        let on = Config::parse("offline = true").unwrap();
+        let off = Config::parse("offline = false").unwrap();
+        assert_eq!(on.args(|_| false), ["--offline=true"]);
+        assert_eq!(off.args(|_| false), ["--offline=false"]);
+    }
+}
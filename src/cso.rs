@@ -0,0 +1,233 @@
+use std::time::Instant;
+
+use anyhow::anyhow;
+use async_std::io::{prelude::BufReadExt, BufReader, WriteExt};
+use async_std::stream::StreamExt;
+
+use crate::limits::{connect_limited, FetchOptions};
+
+/// No entries matched the query, which is not an error for us.
+const NO_MATCHES: u16 = 501;
+
+/// Single line of server response, e.g. `-200:1:        name: Smith, John`.
+#[derive(Debug, PartialEq)]
+struct Line<'a> {
+    code: u16,
+    /// More lines follow
+    continued: bool,
+    text: &'a str,
+}
+
+fn parse_line(line: &str) -> Option<Line<'_>> {
+    let (continued, line) = match line.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (code, text) = line.split_once(':')?;
+    Some(Line {
+        code: code.trim().parse().ok()?,
+        continued,
+        text: text.trim_end_matches(['\r', '\n']),
+    })
+}
+
+/// Splits `1:        name: Smith, John` into entry index, field name and value.
+fn parse_field(text: &str) -> Option<(&str, &str, &str)> {
+    let (index, rest) = text.split_once(':')?;
+    let (name, value) = rest.split_once(':')?;
+    Some((
+        index.trim(),
+        name.trim(),
+        value.strip_prefix(' ').unwrap_or(value),
+    ))
+}
+
+/// Field server keeps for each entry, as described by `fields` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub description: String,
+    /// Field may be used in queries
+    pub lookup: bool,
+}
+
+/// Query result, fields in order server returned them.
+/// Multi-line values are joined with newlines.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Entry {
+    pub fields: Vec<(String, String)>,
+}
+
+/// Sends single command, returning lines of its successful response.
+async fn command(
+    host: &str,
+    port: u16,
+    command: &str,
+    options: &FetchOptions,
+) -> Result<Vec<String>, anyhow::Error> {
+    let connect = options.policy.connect(host, port);
+    let mut stream = connect_limited(host, connect, options, Instant::now()).await?;
+    stream
+        .write_all(format!("{}\r\nquit\r\n", command).as_bytes())
+        .await?;
+    let mut response = Vec::new();
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        let Some(parsed) = parse_line(&line) else {
+            continue;
+        };
+        // informational 1xx lines go before actual response
+        if parsed.continued || parsed.code < 200 {
+            response.push(line);
+            continue;
+        }
+        return match parsed.code {
+            200..=299 => Ok(response),
+            NO_MATCHES => Ok(Vec::new()),
+            code => Err(anyhow!(
+                "ph server responded with {}: {}",
+                code,
+                parsed.text
+            )),
+        };
+    }
+    Err(anyhow!("ph server closed connection unexpectedly"))
+}
+
+/// Runs ph query like `smith` or `name=smith email=*@example.com`.
+pub async fn query(
+    host: &str,
+    port: u16,
+    query: &str,
+    options: &FetchOptions,
+) -> Result<Vec<Entry>, anyhow::Error> {
+    let mut query = query.replace(['\r', '\n'], " ");
+    if !query.split_whitespace().any(|w| w == "return") {
+        query.push_str(" return all");
+    }
+    Ok(parse_entries(
+        &command(host, port, &format!("query {}", query), options).await?,
+    ))
+}
+
+fn parse_entries(lines: &[String]) -> Vec<Entry> {
+    let mut entries: Vec<(String, Entry)> = Vec::new();
+    for line in lines.iter().filter_map(|l| parse_line(l)) {
+        if line.code != 200 {
+            continue;
+        }
+        let Some((index, name, value)) = parse_field(line.text) else {
+            continue;
+        };
+        if entries.last().is_none_or(|(i, _)| i != index) {
+            entries.push((String::from(index), Entry::default()));
+        }
+        let fields = &mut entries.last_mut().unwrap().1.fields;
+        match fields.last_mut() {
+            // empty field name continues previous value
+            Some((_, v)) if name.is_empty() => {
+                v.push('\n');
+                v.push_str(value);
+            }
+            _ => fields.push((String::from(name), String::from(value))),
+        }
+    }
+    entries.into_iter().map(|(_, e)| e).collect()
+}
+
+/// Lists fields server knows about.
+pub async fn fields(
+    host: &str,
+    port: u16,
+    options: &FetchOptions,
+) -> Result<Vec<Field>, anyhow::Error> {
+    Ok(parse_fields(&command(host, port, "fields", options).await?))
+}
+
+fn parse_fields(lines: &[String]) -> Vec<Field> {
+    let mut fields: Vec<(String, Field)> = Vec::new();
+    for line in lines.iter().filter_map(|l| parse_line(l)) {
+        let Some((index, name, text)) = parse_field(line.text) else {
+            continue;
+        };
+        match fields.last_mut() {
+            // first line has properties of the field, second one its description
+            Some((i, field)) if i == index => field.description = String::from(text.trim()),
+            _ => fields.push((
+                String::from(index),
+                Field {
+                    name: String::from(name),
+                    description: String::new(),
+                    lookup: text.split_whitespace().any(|p| p == "Lookup"),
+                },
+            )),
+        }
+    }
+    fields.into_iter().map(|(_, f)| f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn parsing_responses() {
+        assert_eq!(
+            parse_line("102:There were 2 matches to your request."),
+            Some(Line {
+                code: 102,
+                continued: false,
+                text: "There were 2 matches to your request."
+            })
+        );
+        assert_eq!(parse_line("garbage"), None);
+
+        let entries = parse_entries(&lines(
+            "102:There were 2 matches to your request.\n\
+            -200:1:        name: Smith, John\n\
+            -200:1:     address: 1 Main St\n\
+            -200:1:            : Springfield\n\
+            -200:2:        name: Smith, Jane\n\
+            -508:2:       phone: Not present in entry.",
+        ));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].fields,
+            vec![
+                (String::from("name"), String::from("Smith, John")),
+                (
+                    String::from("address"),
+                    String::from("1 Main St\nSpringfield")
+                ),
+            ]
+        );
+        assert_eq!(entries[1].fields.len(), 1);
+
+        let fields = parse_fields(&lines(
+            "-200:1:name:max 64 Indexed Lookup Public Default\n\
+            -200:1:name:Full name.\n\
+            -200:2:phone:max 64 Public Default\n\
+            -200:2:phone:Office phone number.",
+        ));
+        assert_eq!(
+            fields,
+            vec![
+                Field {
+                    name: String::from("name"),
+                    description: String::from("Full name."),
+                    lookup: true
+                },
+                Field {
+                    name: String::from("phone"),
+                    description: String::from("Office phone number."),
+                    lookup: false
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_std::io::{self, BufRead, Read};
+
+/// Decoder of line based binary-to-text encoding.
+pub trait Decoder {
+    /// Decodes single line, appending decoded bytes to output.
+    fn decode_line(&mut self, line: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+    /// Original name of the file, once decoder has seen it.
+    fn filename(&self) -> Option<&str>;
+    /// Nothing is left to decode, rest of the input is ignored.
+    fn finished(&self) -> bool;
+}
+
+/// uuencoded file, i.e. `begin 644 name` followed by data lines and `end`.
+#[derive(Debug, Default)]
+pub struct UuDecoder {
+    filename: Option<String>,
+    finished: bool,
+}
+
+impl Decoder for UuDecoder {
+    fn decode_line(&mut self, line: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let line = line.trim_ascii_end();
+        if self.filename.is_none() {
+            // anything before `begin` line is just a commentary
+            if let Some(header) = line.strip_prefix(b"begin ") {
+                let header = String::from_utf8_lossy(header);
+                let name = header
+                    .trim()
+                    .split_once(' ')
+                    .map_or("", |(_, name)| name.trim());
+                self.filename = Some(String::from(if name.is_empty() { "decoded" } else { name }));
+            }
+            return Ok(());
+        }
+        if line == b"end" {
+            self.finished = true;
+            return Ok(());
+        }
+        let Some((&len, data)) = line.split_first() else {
+            return Ok(());
+        };
+        let len = usize::from(uu_value(len));
+        let data: Vec<u8> = data.iter().map(|&c| uu_value(c)).collect();
+        let mut decoded = Vec::with_capacity(len + 2);
+        for group in data.chunks(4) {
+            // trailing spaces may be stripped by servers, treat missing chars as zeroes
+            let c = |i: usize| group.get(i).copied().unwrap_or(0);
+            decoded.push(c(0) << 2 | c(1) >> 4);
+            decoded.push(c(1) << 4 | c(2) >> 2);
+            decoded.push(c(2) << 6 | c(3));
+        }
+        if decoded.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "uuencoded line is too short",
+            ));
+        }
+        output.extend_from_slice(&decoded[..len]);
+        Ok(())
+    }
+
+    fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+fn uu_value(c: u8) -> u8 {
+    c.wrapping_sub(b' ') & 0x3f
+}
+
+const BINHEX_ALPHABET: &[u8] = b"!\"#$%&'()*+,-012345689@ABCDEFGHIJKLMNPQRSTUVXYZ[`abcdefhijklmpqr";
+const BINHEX_RUN: u8 = 0x90;
+
+#[derive(Debug, Default, PartialEq)]
+enum BinHexState {
+    /// Waiting for `:` that starts encoded data
+    #[default]
+    Preamble,
+    Header,
+    DataFork {
+        left: usize,
+    },
+    Done,
+}
+
+/// BinHex 4.0 file, only its data fork is kept.
+#[derive(Debug, Default)]
+pub struct BinHexDecoder {
+    state: BinHexState,
+    /// Bits not yet forming a whole byte
+    bits: u32,
+    nbits: u8,
+    /// Run length decoding state
+    last: u8,
+    run: bool,
+    header: Vec<u8>,
+    filename: Option<String>,
+}
+
+impl BinHexDecoder {
+    /// Feeds byte decoded from 6-bit characters into run length decoder.
+    fn push_byte(&mut self, b: u8, output: &mut Vec<u8>) {
+        if self.run {
+            self.run = false;
+            if b == 0 {
+                self.last = BINHEX_RUN;
+                self.push_decoded(BINHEX_RUN, output);
+            } else {
+                // previous byte is repeated `b` times, including occurrence already emitted
+                for _ in 1..b {
+                    self.push_decoded(self.last, output);
+                }
+            }
+        } else if b == BINHEX_RUN {
+            self.run = true;
+        } else {
+            self.last = b;
+            self.push_decoded(b, output);
+        }
+    }
+
+    fn push_decoded(&mut self, b: u8, output: &mut Vec<u8>) {
+        match self.state {
+            BinHexState::Header => {
+                self.header.push(b);
+                let name_len = usize::from(self.header[0]);
+                // name, version, type, creator, flags, data and resource lengths, crc
+                if self.header.len() == 1 + name_len + 1 + 4 + 4 + 2 + 4 + 4 + 2 {
+                    let name = &self.header[1..1 + name_len];
+                    self.filename = Some(String::from_utf8_lossy(name).into_owned());
+                    let at = 1 + name_len + 1 + 4 + 4 + 2;
+                    let len = u32::from_be_bytes(self.header[at..at + 4].try_into().unwrap());
+                    self.state = match len {
+                        0 => BinHexState::Done,
+                        len => BinHexState::DataFork { left: len as usize },
+                    };
+                }
+            }
+            BinHexState::DataFork { left } => {
+                output.push(b);
+                self.state = match left - 1 {
+                    0 => BinHexState::Done,
+                    left => BinHexState::DataFork { left },
+                };
+            }
+            BinHexState::Preamble | BinHexState::Done => {}
+        }
+    }
+}
+
+impl Decoder for BinHexDecoder {
+    fn decode_line(&mut self, line: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let mut line = line.trim_ascii();
+        if self.state == BinHexState::Preamble {
+            match line.strip_prefix(b":") {
+                Some(data) => {
+                    self.state = BinHexState::Header;
+                    line = data;
+                }
+                None => return Ok(()),
+            }
+        }
+        for &c in line {
+            if c == b':' {
+                self.state = BinHexState::Done;
+            }
+            if self.state == BinHexState::Done {
+                break;
+            }
+            let Some(v) = BINHEX_ALPHABET.iter().position(|&a| a == c) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid BinHex character {:?}", char::from(c)),
+                ));
+            };
+            self.bits = self.bits << 6 | v as u32;
+            self.nbits += 6;
+            if self.nbits >= 8 {
+                self.nbits -= 8;
+                self.push_byte((self.bits >> self.nbits) as u8, output);
+                self.bits &= (1 << self.nbits) - 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    fn finished(&self) -> bool {
+        self.state == BinHexState::Done
+    }
+}
+
+/// Decodes underlying response line by line as it is being read.
+pub struct DecodingReader<R> {
+    inner: R,
+    decoder: Box<dyn Decoder + Send + Sync>,
+    line: Vec<u8>,
+    output: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: BufRead + Unpin> DecodingReader<R> {
+    pub fn new(inner: R, decoder: Box<dyn Decoder + Send + Sync>) -> Self {
+        Self {
+            inner,
+            decoder,
+            line: Vec::new(),
+            output: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Decodes input until original file name is known and at least `len` bytes are decoded,
+    /// so that response headers can be set before streaming the rest.
+    pub async fn prefetch(&mut self, len: usize) -> io::Result<()> {
+        while !self.done && (self.decoder.filename().is_none() || self.buffered().len() < len) {
+            futures_lite::future::poll_fn(|cx| self.poll_decode(cx)).await?;
+        }
+        Ok(())
+    }
+
+    /// Decoded bytes not yet read.
+    pub fn buffered(&self) -> &[u8] {
+        &self.output[self.pos..]
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.decoder.filename()
+    }
+
+    /// Decodes next line of input, if any.
+    fn poll_decode(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let available = ready!(Pin::new(&mut self.inner).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                self.done = true;
+                let line = std::mem::take(&mut self.line);
+                return Poll::Ready(self.decode(&line));
+            }
+            match available.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.line.extend_from_slice(&available[..=end]);
+                    Pin::new(&mut self.inner).consume(end + 1);
+                    let line = std::mem::take(&mut self.line);
+                    return Poll::Ready(self.decode(&line));
+                }
+                None => {
+                    let len = available.len();
+                    self.line.extend_from_slice(available);
+                    Pin::new(&mut self.inner).consume(len);
+                }
+            }
+        }
+    }
+
+    fn decode(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.pos == self.output.len() {
+            self.output.clear();
+            self.pos = 0;
+        }
+        self.decoder.decode_line(line, &mut self.output)?;
+        self.done |= self.decoder.finished();
+        Ok(())
+    }
+}
+
+impl<R: BufRead + Unpin> Read for DecodingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let buffered = this.buffered();
+            if !buffered.is_empty() {
+                let n = buffered.len().min(buf.len());
+                buf[..n].copy_from_slice(&buffered[..n]);
+                this.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.done {
+                return Poll::Ready(Ok(0));
+            }
+            ready!(this.poll_decode(cx))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{Cursor, ReadExt};
+
+    async fn decode(input: &str, decoder: Box<dyn Decoder + Send + Sync>) -> (String, Vec<u8>) {
+        let mut reader = DecodingReader::new(Cursor::new(input.as_bytes().to_vec()), decoder);
+        reader.prefetch(1).await.unwrap();
+        let filename = String::from(reader.filename().unwrap());
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        (filename, data)
+    }
+
+    #[async_std::test]
+    async fn uudecode() {
+        let (filename, data) = decode(
+            "some text\r\nbegin 644 cat.txt\r\n#0V%T\r\n`\r\nend\r\n.\r\n",
+            Box::new(UuDecoder::default()),
+        )
+        .await;
+        assert_eq!(filename, "cat.txt");
+        assert_eq!(data, b"Cat");
+    }
+
+    #[async_std::test]
+    async fn binhex() {
+        // data fork has run length encoded `o`s and escaped run marker
+        let (filename, data) = decode(
+            "(This file must be converted with BinHex 4.0)\r\n\
+            :#@KPE'a[,R4iG!\"849K8G(4iG!#3\"4F!N!6jR@KPE'a[)(G[N!TbE'3+N!!KL3N\r\n\
+            !!!:\r\n",
+            Box::new(BinHexDecoder::default()),
+        )
+        .await;
+        assert_eq!(filename, "hello.txt");
+        assert_eq!(data, b"hello woooooooooorld\n\x90!");
+    }
+}
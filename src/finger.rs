@@ -0,0 +1,100 @@
+use std::fmt::Display;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use async_std::io::{ReadExt, WriteExt};
+
+use crate::limits::{connect_limited, FetchOptions};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FingerURL {
+    pub host: String,
+    pub port: u16,
+    /// Empty user asks server for the list of logged in users
+    pub user: String,
+}
+
+impl TryFrom<&str> for FingerURL {
+    type Error = anyhow::Error;
+    fn try_from(url_str: &str) -> Result<Self, Self::Error> {
+        // both finger://user@host and finger://host/user forms are in use
+        let finger_url_re = regex_static::static_regex!(
+            r#"^finger://(?:(?P<user>[^@/]*)@)?(?P<host>[^:/@]+)(?::(?P<port>\d+))?(?:/(?P<path_user>.*))?$"#
+        );
+        let Some(caps) = finger_url_re.captures(url_str) else {
+            return Err(anyhow!("failed to parse URL"));
+        };
+        let user = caps
+            .name("user")
+            .or(caps.name("path_user"))
+            .map(|u| u.as_str())
+            .unwrap_or_default();
+        Ok(Self {
+            host: String::from(&caps["host"]),
+            port: match caps.name("port") {
+                Some(p) => p.as_str().parse()?,
+                None => 79,
+            },
+            user: urlencoding::decode(user)?.into_owned(),
+        })
+    }
+}
+
+impl Display for FingerURL {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "finger://")?;
+        if !self.user.is_empty() {
+            write!(f, "{}@", self.user)?;
+        }
+        write!(f, "{}", self.host)?;
+        if self.port != 79 {
+            write!(f, ":{}", self.port)?;
+        }
+        Ok(())
+    }
+}
+
+impl FingerURL {
+    pub fn new(host: &str, port: u16, user: &str) -> Self {
+        Self {
+            host: String::from(host),
+            port,
+            user: String::from(user),
+        }
+    }
+}
+
+/// Queries finger server, returning its response as text.
+pub async fn fetch_url(url: &FingerURL, options: &FetchOptions) -> Result<String, anyhow::Error> {
+    let connect = options.policy.connect(&url.host, url.port);
+    let mut stream = connect_limited(&url.host, connect, options, Instant::now()).await?;
+    stream
+        .write_all(format!("{}\r\n", url.user).as_bytes())
+        .await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(String::from_utf8_lossy(&response).replace("\r\n", "\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_urls() {
+        let u = FingerURL::try_from("finger://user@example.com").unwrap();
+        assert_eq!(u.host, "example.com");
+        assert_eq!(u.port, 79);
+        assert_eq!(u.user, "user");
+        assert_eq!(u.to_string(), "finger://user@example.com");
+
+        let u = FingerURL::try_from("finger://example.com:7979/user").unwrap();
+        assert_eq!(u.port, 7979);
+        assert_eq!(u.user, "user");
+        assert_eq!(u.to_string(), "finger://user@example.com:7979");
+
+        let u = FingerURL::try_from("finger://example.com").unwrap();
+        assert_eq!(u.user, "");
+        assert!(FingerURL::try_from("gopher://example.com").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::policy::Cidr;
+
+/// Finds address of the client behind reverse proxies.
+///
+/// Proxies append address they got request from to `X-Forwarded-For` or `Forwarded` header.
+/// Those are walked from the end for as long as the address request came from is a trusted
+/// proxy, anything before that could be made up by client. If both are present,
+/// `X-Forwarded-For` is used, as common proxies like nginx append to it and pass
+/// `Forwarded` sent by client on as is.
+pub fn client_ip(
+    peer: IpAddr,
+    forwarded: Option<&str>,
+    x_forwarded_for: Option<&str>,
+    trusted: &[Cidr],
+) -> IpAddr {
+    let hops: Vec<Option<IpAddr>> = match (forwarded, x_forwarded_for) {
+        (_, Some(x_forwarded_for)) => x_forwarded_for.split(',').map(parse_node).collect(),
+        (Some(forwarded), None) => forwarded
+            .split(',')
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value))
+            })
+            .collect(),
+        (None, None) => Vec::new(),
+    };
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        if !trusted.iter().any(|c| c.contains(client)) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = ip,
+            // obfuscated or unknown, trusted proxy is the best we've got
+            None => break,
+        }
+    }
+    client
+}
+
+/// Finds host the client addressed, i.e. the last `X-Forwarded-Host` value if request came
+/// from a trusted proxy, which may have rewritten `Host`, and `Host` itself otherwise.
+pub fn host<'a>(
+    peer: IpAddr,
+    host: &'a str,
+    x_forwarded_host: Option<&'a str>,
+    trusted: &[Cidr],
+) -> &'a str {
+    match x_forwarded_host.and_then(|h| h.rsplit(',').next()) {
+        Some(forwarded) if trusted.iter().any(|c| c.contains(peer)) => forwarded.trim(),
+        _ => host,
+    }
+}
+
+/// Parses `192.0.2.1`, `"192.0.2.1:8080"`, `2001:db8::1` or `"[2001:db8::1]:8080"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client() {
+        let trusted: Vec<Cidr> = vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let client = |peer, forwarded, xff| client_ip(ip(peer), forwarded, xff, &trusted);
+
+        assert_eq!(client("127.0.0.1", None, None), ip("127.0.0.1"));
+        assert_eq!(
+            client("127.0.0.1", None, Some("203.0.113.9, 198.51.100.7")),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            client("127.0.0.1", None, Some("203.0.113.9, 10.1.1.1")),
+            ip("203.0.113.9")
+        );
+        // untrusted peer can't claim to be anyone
+        assert_eq!(
+            client("192.0.2.1", None, Some("203.0.113.9")),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            client(
+                "127.0.0.1",
+                Some(r#"for=192.0.2.43, for="[2001:db8:cafe::17]:4711";proto=https"#),
+                None
+            ),
+            ip("2001:db8:cafe::17")
+        );
+        // Forwarded made up by client is passed on by proxy appending to X-Forwarded-For
+        assert_eq!(
+            client("127.0.0.1", Some("for=10.9.9.9"), Some("203.0.113.9")),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            client("127.0.0.1", Some("for=_hidden;by=10.1.1.1"), None),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_host() {
+        let trusted: Vec<Cidr> = vec!["127.0.0.1".parse().unwrap()];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let xfh = Some("evil.example, proxy70.example");
+        assert_eq!(
+            host(ip("127.0.0.1"), "127.0.0.1:8080", xfh, &trusted),
+            "proxy70.example"
+        );
+        assert_eq!(
+            host(ip("127.0.0.1"), "proxy70.example", None, &trusted),
+            "proxy70.example"
+        );
+        // untrusted peer can't claim to be addressed as anyone
+        assert_eq!(
+            host(ip("192.0.2.1"), "192.0.2.10", xfh, &trusted),
+            "192.0.2.10"
+        );
+    }
+}
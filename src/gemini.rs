@@ -0,0 +1,375 @@
+use std::fmt::Display;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use async_std::io::{prelude::BufReadExt, BufReader, ReadExt, WriteExt};
+use tide::log;
+
+use crate::limits::{connect_limited, FetchOptions};
+use crate::tls::{Lenient, TlsConfig};
+
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeminiURL {
+    pub host: String,
+    pub port: u16,
+    /// Path along with query string, if any
+    pub path: String,
+}
+
+impl TryFrom<&str> for GeminiURL {
+    type Error = anyhow::Error;
+    fn try_from(url_str: &str) -> Result<Self, Self::Error> {
+        let gemini_url_re = regex_static::static_regex!(
+            r#"^gemini://(?P<host>[^:/?#]+)(?::(?P<port>\d+))?(?P<path>[^#]*)"#
+        );
+        let Some(caps) = gemini_url_re.captures(url_str) else {
+            return Err(anyhow!("failed to parse URL"));
+        };
+        Ok(Self {
+            host: String::from(&caps["host"]),
+            port: match caps.name("port") {
+                Some(p) => p.as_str().parse()?,
+                None => 1965,
+            },
+            path: match &caps["path"] {
+                "" => String::from("/"),
+                p => String::from(p),
+            },
+        })
+    }
+}
+
+impl Display for GeminiURL {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.port == 1965 {
+            write!(f, "gemini://{}{}", self.host, self.path)
+        } else {
+            write!(f, "gemini://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+impl GeminiURL {
+    /// Same URL with query string replaced by user input.
+    pub fn with_input(&self, input: &str) -> Self {
+        let path = self.path.split('?').next().unwrap_or_default();
+        Self {
+            host: self.host.clone(),
+            port: self.port,
+            path: format!("{}?{}", path, urlencoding::encode(input)),
+        }
+    }
+
+    /// Resolves link found on this page into absolute URL.
+    pub fn join(&self, link: &str) -> String {
+        let scheme_re = regex_static::static_regex!(r#"^[a-zA-Z][a-zA-Z0-9+.-]*:"#);
+        if scheme_re.is_match(link) {
+            return String::from(link);
+        }
+        if let Some(rest) = link.strip_prefix("//") {
+            return format!("gemini://{}", rest);
+        }
+        let base = Self {
+            path: String::new(),
+            ..self.clone()
+        };
+        let path = if link.starts_with('/') {
+            String::from(link)
+        } else {
+            let dir = self.path.split('?').next().unwrap_or_default();
+            let dir = &dir[..dir.rfind('/').map(|i| i + 1).unwrap_or(0)];
+            format!("{}{}", dir, link)
+        };
+        format!("{}{}", base, normalize_path(&path))
+    }
+}
+
+/// Removes `.` and `..` segments from the path.
+fn normalize_path(path: &str) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((p, q)) => (p, Some(q)),
+        None => (path, None),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    if path.ends_with("/.") || path.ends_with("/..") {
+        segments.push("");
+    }
+    let mut result = format!("/{}", segments.join("/"));
+    if let Some(q) = query {
+        result.push('?');
+        result.push_str(q);
+    }
+    result
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// Server asks for user input, sensitive input should not be echoed
+    Input {
+        prompt: String,
+        sensitive: bool,
+    },
+    Success {
+        mime: String,
+        body: Vec<u8>,
+    },
+    /// Request failed, either temporary or permanently, or needs client certificate
+    Failure {
+        status: u8,
+        message: String,
+    },
+}
+
+/// Parses `<STATUS><SPACE><META>` response header.
+fn parse_header(line: &str) -> Result<(u8, String), anyhow::Error> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (status, meta) = line.split_once(' ').unwrap_or((line, ""));
+    match status.parse::<u8>() {
+        Ok(code) if status.len() == 2 && (10..=69).contains(&code) => {
+            Ok((code, String::from(meta.trim())))
+        }
+        _ => Err(anyhow!("invalid gemini response header {:?}", line)),
+    }
+}
+
+/// Fetches gemini resource following redirects.
+/// Returns the response along with the URL it was eventually fetched from.
+pub async fn fetch_url(
+    url: &GeminiURL,
+    tls: &TlsConfig,
+    options: &FetchOptions,
+) -> Result<(Response, GeminiURL), anyhow::Error> {
+    let started = Instant::now();
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let connect = async {
+            let stream = options.policy.connect(&url.host, url.port).await?;
+            Ok(tls.handshake(stream, &url.host, url.port).await?)
+        };
+        let mut stream = connect_limited(&url.host, connect, options, started).await?;
+        stream.write_all(format!("{}\r\n", url).as_bytes()).await?;
+        // gemini responses are not cached, so truncation is tolerated
+        let mut reader = BufReader::new(Lenient(stream));
+        let mut header = String::new();
+        reader.read_line(&mut header).await?;
+        let (status, meta) = parse_header(&header)?;
+        log::info!("gemini {} -> {} {}", url, status, meta);
+        let response = match status / 10 {
+            1 => Response::Input {
+                prompt: meta,
+                sensitive: status == 11,
+            },
+            2 => {
+                let mut body = Vec::new();
+                reader.read_to_end(&mut body).await?;
+                Response::Success {
+                    mime: match meta.as_str() {
+                        "" => String::from("text/gemini; charset=utf-8"),
+                        m => String::from(m),
+                    },
+                    body,
+                }
+            }
+            3 => {
+                let target = url.join(&meta);
+                url = GeminiURL::try_from(target.as_str())
+                    .map_err(|_| anyhow!("redirect to non-gemini URL {}", target))?;
+                continue;
+            }
+            6 => Response::Failure {
+                status,
+                message: format!("client certificate required: {}", meta),
+            },
+            _ => Response::Failure {
+                status,
+                message: meta,
+            },
+        };
+        return Ok((response, url));
+    }
+    Err(anyhow!("too many redirects"))
+}
+
+/// Schemes of links followed through proxy.
+const PROXIED_SCHEMES: &[&str] = &["gemini", "gopher", "gophers", "finger"];
+/// Schemes of links left for browser to follow.
+const SAFE_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Converts link target into proxy href, or `None` if it is not safe to follow.
+fn link_href(base: &GeminiURL, link: &str) -> Option<String> {
+    url_href(&base.join(link))
+}
+
+/// Converts absolute URL into proxy href, or `None` if it is not safe to follow,
+/// like `javascript:` ones.
+pub fn url_href(target: &str) -> Option<String> {
+    let scheme = target.split_once(':')?.0.to_ascii_lowercase();
+    if PROXIED_SCHEMES.contains(&scheme.as_str()) {
+        Some(format!("?url={}", urlencoding::encode(target)))
+    } else if SAFE_SCHEMES.contains(&scheme.as_str()) {
+        Some(String::from(target))
+    } else {
+        None
+    }
+}
+
+/// Renders `text/gemini` document into HTML.
+pub fn render(text: &str, base: &GeminiURL) -> String {
+    let mut body = String::new();
+    let mut preformatted = false;
+    let mut in_list = false;
+    for line in text.lines() {
+        if line.starts_with("```") {
+            if in_list {
+                body.push_str("</ul>\n");
+                in_list = false;
+            }
+            body.push_str(if preformatted { "</pre>\n" } else { "<pre>\n" });
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted {
+            body.push_str(&html_escape::encode_text(line));
+            body.push('\n');
+            continue;
+        }
+        let item = line.strip_prefix("* ");
+        if in_list && item.is_none() {
+            body.push_str("</ul>\n");
+        } else if !in_list && item.is_some() {
+            body.push_str("<ul>\n");
+        }
+        in_list = item.is_some();
+        if let Some(item) = item {
+            body.push_str(&format!("<li>{}</li>\n", html_escape::encode_text(item)));
+        } else if let Some(link) = line.strip_prefix("=>") {
+            let link = link.trim();
+            let (target, label) = match link.split_once(char::is_whitespace) {
+                Some((t, l)) => (t, l.trim()),
+                None => (link, link),
+            };
+            match link_href(base, target) {
+                Some(href) => body.push_str(&format!(
+                    "<p><a href=\"{}\">{}</a></p>\n",
+                    html_escape::encode_double_quoted_attribute(&href),
+                    html_escape::encode_text(label)
+                )),
+                None => body.push_str(&format!(
+                    "<p>{} ({})</p>\n",
+                    html_escape::encode_text(label),
+                    html_escape::encode_text(target)
+                )),
+            }
+        } else if let Some(h) = line.strip_prefix("###") {
+            body.push_str(&format!(
+                "<h3>{}</h3>\n",
+                html_escape::encode_text(h.trim())
+            ));
+        } else if let Some(h) = line.strip_prefix("##") {
+            body.push_str(&format!(
+                "<h2>{}</h2>\n",
+                html_escape::encode_text(h.trim())
+            ));
+        } else if let Some(h) = line.strip_prefix('#') {
+            body.push_str(&format!(
+                "<h1>{}</h1>\n",
+                html_escape::encode_text(h.trim())
+            ));
+        } else if let Some(q) = line.strip_prefix('>') {
+            body.push_str(&format!(
+                "<blockquote>{}</blockquote>\n",
+                html_escape::encode_text(q.trim())
+            ));
+        } else if line.trim().is_empty() {
+            body.push_str("<br>\n");
+        } else {
+            body.push_str(&format!("<p>{}</p>\n", html_escape::encode_text(line)));
+        }
+    }
+    if preformatted {
+        body.push_str("</pre>\n");
+    }
+    if in_list {
+        body.push_str("</ul>\n");
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_urls() {
+        let u = GeminiURL::try_from("gemini://example.com").unwrap();
+        assert_eq!(u.port, 1965);
+        assert_eq!(u.path, "/");
+        assert_eq!(u.to_string(), "gemini://example.com/");
+
+        let u = GeminiURL::try_from("gemini://example.com:1966/dir/page.gmi?q=1").unwrap();
+        assert_eq!(u.port, 1966);
+        assert_eq!(u.path, "/dir/page.gmi?q=1");
+        assert_eq!(
+            u.join("other.gmi"),
+            "gemini://example.com:1966/dir/other.gmi"
+        );
+        assert_eq!(u.join("../up/"), "gemini://example.com:1966/up/");
+        assert_eq!(u.join("/root"), "gemini://example.com:1966/root");
+        assert_eq!(u.join("//other.org/x"), "gemini://other.org/x");
+        assert_eq!(u.join("https://example.org"), "https://example.org");
+        assert_eq!(
+            u.with_input("hello world").to_string(),
+            "gemini://example.com:1966/dir/page.gmi?hello%20world"
+        );
+    }
+
+    #[test]
+    fn parsing_headers() {
+        assert_eq!(
+            parse_header("20 text/gemini\r\n").unwrap(),
+            (20, String::from("text/gemini"))
+        );
+        assert_eq!(parse_header("51\r\n").unwrap(), (51, String::new()));
+        assert!(parse_header("HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_header("7 nope\r\n").is_err());
+    }
+
+    #[test]
+    fn rendering() {
+        let base = GeminiURL::try_from("gemini://example.com/log/").unwrap();
+        let html = render(
+            "# Title\nSome <text>\n=> post.gmi First post\n* one\n* two\n> quote\n```\n* raw\n```",
+            &base,
+        );
+        assert_eq!(
+            html,
+            "<h1>Title</h1>\n\
+            <p>Some &lt;text&gt;</p>\n\
+            <p><a href=\"?url=gemini%3A%2F%2Fexample.com%2Flog%2Fpost.gmi\">First post</a></p>\n\
+            <ul>\n<li>one</li>\n<li>two</li>\n</ul>\n\
+            <blockquote>quote</blockquote>\n\
+            <pre>\n* raw\n</pre>\n"
+        );
+
+        assert_eq!(
+            render(
+                "=> JavaScript:alert(1) Click\n=> https://example.org Web\n=> finger://example.com/user",
+                &base
+            ),
+            "<p>Click (JavaScript:alert(1))</p>\n\
+            <p><a href=\"https://example.org\">Web</a></p>\n\
+            <p><a href=\"?url=finger%3A%2F%2Fexample.com%2Fuser\">finger://example.com/user</a></p>\n"
+        );
+    }
+}
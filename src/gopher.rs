@@ -1,13 +1,11 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::str::FromStr;
+use std::time::Instant;
 
 use ansitok::{parse_ansi, parse_ansi_sgr, AnsiColor, ElementKind, VisualAttribute};
 use anyhow::anyhow;
-use async_std::stream::StreamExt;
-use async_std::{
-    io::{prelude::BufReadExt, BufReader, Cursor, ReadExt, WriteExt},
-    net::TcpStream,
-};
+use async_std::io::{self, prelude::BufReadExt, BufReader, Cursor, Read, ReadExt, Write, WriteExt};
 
 use serde::Deserialize;
 use tide::{
@@ -15,13 +13,27 @@ use tide::{
     log,
 };
 
+use crate::finger::FingerURL;
+use crate::gemini;
+use crate::limits::{self, FetchOptions, Throttled};
+use crate::metrics::METRICS;
+use crate::policy::Policy;
+use crate::sniff;
+use crate::tls::{self, Lenient, TlsConfig};
+
+/// Plain gopher servers won't answer TLS handshake, they just wait for selector to arrive.
+const OPPORTUNISTIC_TLS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
 const _INVALID_ENTRY: DirEntry = DirEntry {
     item_type: GopherItem::Unknown,
     label: String::new(),
     url: None,
+    plus: false,
+    ask: false,
 };
 
-const _ANSI_COLORS: &'static [&str] = &[
+/// xterm 256 colour palette, also used by in-page telnet terminal.
+pub const _ANSI_COLORS: &[&str] = &[
     "#000000", "#800000", "#008000", "#808000", "#000080", "#800080", "#008080", "#c0c0c0",
     "#808080", "#ff0000", "#00ff00", "#ffff00", "#0000ff", "#ff00ff", "#00ffff", "#ffffff",
     "#000000", "#00005f", "#000087", "#0000af", "#0000d7", "#0000ff", "#005f00", "#005f5f",
@@ -56,7 +68,7 @@ const _ANSI_COLORS: &'static [&str] = &[
     "#a8a8a8", "#b2b2b2", "#bcbcbc", "#c6c6c6", "#d0d0d0", "#dadada", "#e4e4e4", "#eeeeee",
 ];
 
-#[derive(PartialEq, Debug, Deserialize, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Deserialize, Clone, Copy)]
 pub enum GopherItem {
     TextFile,
     Submenu,
@@ -119,97 +131,131 @@ impl From<char> for GopherItem {
     }
 }
 
-impl Into<char> for GopherItem {
-    fn into(self) -> char {
-        match self {
-            Self::TextFile => '0',
-            Self::Submenu => '1',
-            Self::Nameserver => '2',
-            Self::Error => '3',
-            Self::BinHex => '4',
-            Self::Dos => '5',
-            Self::UuencodeFile => '6',
-            Self::FullTextSearch => '7',
-            Self::Telnet => '8',
-            Self::BinaryFile => '9',
-            Self::Mirror => '+',
-            Self::GifFile => 'g',
-            Self::ImageFile => 'I',
-            Self::Telnet3270 => 'T',
-            Self::BitmapFile => ':',
-            Self::MovieFile => ';',
-            Self::SoundFile => '<',
-            Self::DocFile => 'd',
-            Self::HtmlFile => 'h',
-            Self::Info => 'i',
-            Self::PngFile => 'p',
-            Self::RtfFile => 'r',
-            Self::WavFile => 's',
-            Self::PdfFile => 'P',
-            Self::XmlFile => 'X',
-            Self::Unknown => '?',
-        }
-    }
-}
-
-impl Into<Mime> for GopherItem {
-    fn into(self) -> Mime {
-        match self {
-            Self::TextFile => mime::PLAIN,
-            Self::Submenu => mime::HTML,
-            Self::Nameserver => mime::PLAIN,
-            Self::Error => mime::PLAIN,
-            Self::BinHex => mime::BYTE_STREAM,
-            Self::Dos => mime::BYTE_STREAM,
-            Self::UuencodeFile => mime::PLAIN,
-            Self::FullTextSearch => mime::HTML,
-            Self::Telnet => mime::PLAIN,
-            Self::BinaryFile => mime::BYTE_STREAM,
-            Self::Mirror => mime::PLAIN,
-            Self::GifFile => Mime::from_str("image/gif").unwrap_or(mime::BYTE_STREAM),
-            Self::ImageFile => mime::JPEG,
-            Self::Telnet3270 => mime::PLAIN,
-            Self::BitmapFile => Mime::from_str("image/bmp").unwrap_or(mime::BYTE_STREAM),
-            Self::MovieFile => mime::BYTE_STREAM,
-            Self::SoundFile => mime::BYTE_STREAM,
-            Self::DocFile => mime::BYTE_STREAM,
-            Self::HtmlFile => mime::HTML,
-            Self::Info => mime::PLAIN,
-            Self::PngFile => mime::PNG,
-            Self::RtfFile => mime::BYTE_STREAM,
-            Self::WavFile => mime::BYTE_STREAM,
-            Self::PdfFile => Mime::from_str("application/pdf").unwrap_or(mime::BYTE_STREAM),
-            Self::XmlFile => mime::XML,
-            Self::Unknown => mime::PLAIN,
+impl From<GopherItem> for char {
+    fn from(item: GopherItem) -> char {
+        match item {
+            GopherItem::TextFile => '0',
+            GopherItem::Submenu => '1',
+            GopherItem::Nameserver => '2',
+            GopherItem::Error => '3',
+            GopherItem::BinHex => '4',
+            GopherItem::Dos => '5',
+            GopherItem::UuencodeFile => '6',
+            GopherItem::FullTextSearch => '7',
+            GopherItem::Telnet => '8',
+            GopherItem::BinaryFile => '9',
+            GopherItem::Mirror => '+',
+            GopherItem::GifFile => 'g',
+            GopherItem::ImageFile => 'I',
+            GopherItem::Telnet3270 => 'T',
+            GopherItem::BitmapFile => ':',
+            GopherItem::MovieFile => ';',
+            GopherItem::SoundFile => '<',
+            GopherItem::DocFile => 'd',
+            GopherItem::HtmlFile => 'h',
+            GopherItem::Info => 'i',
+            GopherItem::PngFile => 'p',
+            GopherItem::RtfFile => 'r',
+            GopherItem::WavFile => 's',
+            GopherItem::PdfFile => 'P',
+            GopherItem::XmlFile => 'X',
+            GopherItem::Unknown => '?',
+        }
+    }
+}
+
+impl From<GopherItem> for Mime {
+    fn from(item: GopherItem) -> Mime {
+        match item {
+            GopherItem::TextFile => mime::PLAIN,
+            GopherItem::Submenu => mime::HTML,
+            GopherItem::Nameserver => mime::PLAIN,
+            GopherItem::Error => mime::PLAIN,
+            GopherItem::BinHex => mime::BYTE_STREAM,
+            GopherItem::Dos => mime::BYTE_STREAM,
+            GopherItem::UuencodeFile => mime::PLAIN,
+            GopherItem::FullTextSearch => mime::HTML,
+            GopherItem::Telnet => mime::PLAIN,
+            GopherItem::BinaryFile => mime::BYTE_STREAM,
+            GopherItem::Mirror => mime::PLAIN,
+            GopherItem::GifFile => Mime::from_str("image/gif").unwrap_or(mime::BYTE_STREAM),
+            GopherItem::ImageFile => mime::JPEG,
+            GopherItem::Telnet3270 => mime::PLAIN,
+            GopherItem::BitmapFile => Mime::from_str("image/bmp").unwrap_or(mime::BYTE_STREAM),
+            GopherItem::MovieFile => mime::BYTE_STREAM,
+            GopherItem::SoundFile => mime::BYTE_STREAM,
+            GopherItem::DocFile => mime::BYTE_STREAM,
+            GopherItem::HtmlFile => mime::HTML,
+            GopherItem::Info => mime::PLAIN,
+            GopherItem::PngFile => mime::PNG,
+            GopherItem::RtfFile => mime::BYTE_STREAM,
+            GopherItem::WavFile => mime::BYTE_STREAM,
+            GopherItem::PdfFile => Mime::from_str("application/pdf").unwrap_or(mime::BYTE_STREAM),
+            GopherItem::XmlFile => mime::XML,
+            GopherItem::Unknown => mime::PLAIN,
+        }
+    }
+}
+
+impl GopherItem {
+    /// Guesses item type for Gopher+ view of given MIME type.
+    pub fn from_mime(view: &str) -> Self {
+        match view.split(' ').next().unwrap_or_default() {
+            "application/gopher-menu" | "application/gopher+-menu" => Self::Submenu,
+            "text/html" => Self::HtmlFile,
+            "application/pdf" => Self::PdfFile,
+            "image/gif" => Self::GifFile,
+            "image/png" => Self::PngFile,
+            m if m.starts_with("text/") => Self::TextFile,
+            m if m.starts_with("image/") => Self::ImageFile,
+            m if m.starts_with("audio/") => Self::SoundFile,
+            m if m.starts_with("video/") => Self::MovieFile,
+            _ => Self::BinaryFile,
         }
     }
 }
 
 impl Display for GopherItem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", Into::<char>::into(self.clone()))
+        write!(f, "{}", Into::<char>::into(*self))
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GopherURL {
+    pub tls: bool,
     pub host: String,
     pub port: u16,
     pub gopher_type: GopherItem,
     pub selector: String,
+    /// Gopher+ request string sent after selector, e.g. `!` for item attributes
+    /// or `+text/plain` for alternate view
+    pub plus: Option<String>,
+    /// Alternate servers with the same item, tried in order when this one is unreachable
+    pub mirrors: Vec<GopherURL>,
 }
 
 impl TryFrom<&str> for GopherURL {
     type Error = anyhow::Error;
     fn try_from(url_str: &str) -> Result<Self, Self::Error> {
         let gopher_url_re = regex_static::static_regex!(
-            r#"(?:gopher://)?(?P<host>[^:/]+)(?::(?P<port>\d+))?(?:/(?P<type>[A-z0-9:+:;<?])(?P<selector>.*))?$"#
+            r#"(?:(?P<scheme>gophers?)://)?(?P<host>[^:/]+)(?::(?P<port>\d+))?(?:/(?P<type>[A-z0-9:+:;<?])(?P<selector>.*))?$"#
         );
         let Some(caps) = gopher_url_re.captures(url_str) else {
             return Err(anyhow!("failed to parse URL"));
         };
         log::info!("parsed {} as {:?}", url_str, caps);
+        let selector = caps
+            .name("selector")
+            .map(|s| s.as_str())
+            .unwrap_or_default();
+        // RFC 4266 puts gopher+ string after selector and search, separated by tabs
+        let (selector, plus) = match selector.splitn(3, "%09").collect::<Vec<_>>()[..] {
+            [selector, _, plus] => (selector, Some(urlencoding::decode(plus)?.into_owned())),
+            _ => (selector, None),
+        };
         Ok(Self {
+            tls: caps.name("scheme").is_some_and(|s| s.as_str() == "gophers"),
             host: String::from(caps.name("host").unwrap().as_str()),
             port: match caps.name("port") {
                 Some(p) => p.as_str().parse().unwrap(),
@@ -219,24 +265,28 @@ impl TryFrom<&str> for GopherURL {
                 Some(t) => t.as_str().chars().next().unwrap().into(),
                 None => GopherItem::Submenu,
             },
-            selector: match caps.name("selector") {
-                Some(s) => String::from(s.as_str()),
-                None => String::from(""),
-            },
+            selector: String::from(selector),
+            plus,
+            mirrors: Vec::new(),
         })
     }
 }
 
 impl Display for GopherURL {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.selector.is_empty() {
-            write!(f, "gopher://{}:{}", self.host, self.port)
+        let scheme = if self.tls { "gophers" } else { "gopher" };
+        if self.selector.is_empty() && self.plus.is_none() {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
         } else {
             write!(
                 f,
-                "gopher://{}:{}/{}{}",
-                self.host, self.port, self.gopher_type, self.selector
-            )
+                "{}://{}:{}/{}{}",
+                scheme, self.host, self.port, self.gopher_type, self.selector
+            )?;
+            if let Some(plus) = &self.plus {
+                write!(f, "%09%09{}", urlencoding::encode(plus))?;
+            }
+            Ok(())
         }
     }
 }
@@ -244,21 +294,54 @@ impl Display for GopherURL {
 impl GopherURL {
     fn new(host: &str, port: &str, item_type: &GopherItem, selector: &str) -> Self {
         Self {
+            tls: false,
             host: String::from(host),
-            port: port.parse().unwrap_or(70),
-            gopher_type: item_type.clone(),
+            port: port.trim().parse().unwrap_or(70),
+            gopher_type: *item_type,
             selector: String::from(selector),
+            plus: None,
+            mirrors: Vec::new(),
+        }
+    }
+
+    /// URL to request Gopher+ attribute block of this item.
+    pub fn attributes(&self) -> Self {
+        Self {
+            plus: Some(String::from("!")),
+            ..self.clone()
+        }
+    }
+
+    /// URL to request alternate Gopher+ view of this item, like `application/pdf En_US`.
+    pub fn view(&self, view: &str) -> Self {
+        Self {
+            gopher_type: GopherItem::from_mime(view),
+            plus: Some(format!("+{}", view)),
+            ..self.clone()
         }
     }
 
     fn to_href(&self) -> Result<String, anyhow::Error> {
-        if self.selector.starts_with("URL:") {
-            Ok(String::from(&self.selector[4..]))
-        } else {
+        if let Some(url) = self.selector.strip_prefix("URL:") {
+            // URLs of protocols proxy70 speaks are proxied as well
+            gemini::url_href(url).ok_or_else(|| anyhow!("unsupported URL {}", url))
+        } else if self.port == 79 {
+            // finger links in gophermaps are items with user name as a selector
+            let finger = FingerURL::new(&self.host, self.port, &self.selector);
             Ok(format!(
                 "?url={}",
-                urlencoding::encode(self.to_string().as_str())
+                urlencoding::encode(finger.to_string().as_str())
             ))
+        } else {
+            let mut href = format!("?url={}", urlencoding::encode(self.to_string().as_str()));
+            for (n, mirror) in self.mirrors.iter().enumerate() {
+                href.push_str(&format!(
+                    "&mirrors[{}]={}",
+                    n,
+                    urlencoding::encode(mirror.to_string().as_str())
+                ));
+            }
+            Ok(href)
         }
     }
 }
@@ -268,6 +351,10 @@ pub struct DirEntry {
     pub item_type: GopherItem,
     pub label: String,
     pub url: Option<GopherURL>,
+    /// Item is served by Gopher+ server
+    pub plus: bool,
+    /// Gopher+ item expects user to fill in its ASK block first
+    pub ask: bool,
 }
 
 impl From<&str> for DirEntry {
@@ -283,7 +370,12 @@ impl From<&str> for DirEntry {
                     }
                 };
                 let label: String = s.collect();
-                DirEntry::new(t, label.as_str(), selector, host, port)
+                let mut entry = DirEntry::new(t, label.as_str(), selector, host, port);
+                // Gopher+ servers mark their items with `+` (or `?` for ones with ASK block)
+                let marker = e.next().map(str::trim);
+                entry.plus = matches!(marker, Some("+" | "?"));
+                entry.ask = marker == Some("?");
+                entry
             }
             _ => _INVALID_ENTRY,
         }
@@ -297,11 +389,15 @@ impl DirEntry {
                 item_type,
                 label: String::from(label),
                 url: None,
+                plus: false,
+                ask: false,
             },
             _ => DirEntry {
                 item_type,
                 label: String::from(label),
                 url: Some(GopherURL::new(host, port, &item_type, selector)),
+                plus: false,
+                ask: false,
             },
         }
     }
@@ -309,6 +405,7 @@ impl DirEntry {
     pub fn to_href(&self) -> Option<String> {
         match &self.url {
             Some(url) => match url.to_href() {
+                Ok(href) if self.ask => Some(format!("{}&ask=1", href)),
                 Ok(href) => Some(href),
                 Err(e) => {
                     log::error!("invalid gopher URL: {:?}: {}", self.url, e);
@@ -320,41 +417,101 @@ impl DirEntry {
     }
 
     fn format_label(&self) -> String {
-        match self.to_href() {
+        let label = match self.to_href() {
             Some(url) => format!(
-                r#"<pre><a href="{}"">{}</a></pre>"#,
-                url,
+                r#"<pre><a href="{}">{}</a></pre>"#,
+                html_escape::encode_double_quoted_attribute(&url),
                 &decode_ansi_style(&self.label)
             ),
             None => format!("<pre>{}</pre>", &decode_ansi_style(&self.label)),
+        };
+        label + &self.format_mirrors() + &self.format_attributes()
+    }
+
+    fn format_mirrors(&self) -> String {
+        let Some(url) = self.url.as_ref().filter(|u| !u.mirrors.is_empty()) else {
+            return String::new();
+        };
+        let links: Vec<String> = url
+            .mirrors
+            .iter()
+            .filter_map(|m| {
+                Some(format!(
+                    "<a href=\"{}\">{}:{}</a>",
+                    html_escape::encode_double_quoted_attribute(&m.to_href().ok()?),
+                    html_escape::encode_text(&m.host),
+                    m.port
+                ))
+            })
+            .collect();
+        format!("<div class=\"meta\">mirrors: {}</div>", links.join(" | "))
+    }
+
+    /// Links Gopher+ item to page with its attributes, fetched only when asked for.
+    fn format_attributes(&self) -> String {
+        let Some(url) = self.url.as_ref().filter(|_| self.plus) else {
+            return String::new();
+        };
+        match url.to_href() {
+            Ok(href) => format!(
+                "<div class=\"meta\"><a href=\"{}\">attributes</a></div>",
+                html_escape::encode_double_quoted_attribute(&format!("{}&info=1", href))
+            ),
+            Err(_) => String::new(),
+        }
+    }
+
+    /// Item pointing to the same server as the TLS menu itself is fetched over TLS as well.
+    pub fn inherit_tls(&mut self, base: &GopherURL) {
+        if let Some(url) = self.url.as_mut().filter(|_| base.tls) {
+            url.tls |= url.host == base.host && url.port == base.port;
         }
     }
 
+    /// MIME type of movie in container browsers can play, judging by selector extension.
+    fn video_type(&self) -> Option<Mime> {
+        let url = self.url.as_ref()?;
+        sniff::sniff(&[], Some(&url.selector)).filter(|m| m.basetype() == "video")
+    }
+
     pub fn format_row(&self) -> Option<String> {
-        match self.item_type {
-            GopherItem::Unknown => None,
-            GopherItem::Info => Some(format!("<td></td><td>{}</td>", self.format_label())),
-            GopherItem::Submenu => Some(format!(
+        // media items without a safe link are rendered as plain ones
+        let href = self
+            .to_href()
+            .map(|h| String::from(html_escape::encode_double_quoted_attribute(&h)));
+        match (self.item_type, href) {
+            (GopherItem::Unknown, _) => None,
+            (GopherItem::Info, _) => Some(format!("<td></td><td>{}</td>", self.format_label())),
+            (GopherItem::Submenu, _) => Some(format!(
                 "<td><i class=\"fa fa-folder-o\"></i></td><td>{}</td>",
                 self.format_label()
             )),
-            GopherItem::TextFile => Some(format!(
+            (GopherItem::TextFile, _) => Some(format!(
                 "<td><i class=\"fa fa-file-text-o\"></i></td><td>{}</td>",
                 self.format_label()
             )),
-            GopherItem::HtmlFile => Some(format!(
+            (GopherItem::HtmlFile, _) => Some(format!(
                 "<td><i class=\"fa fa-external-link\"></i></td><td>{}</td>",
                 self.format_label()
             )),
-            GopherItem::WavFile | GopherItem::SoundFile => Some(format!(
+            (GopherItem::WavFile | GopherItem::SoundFile, Some(href)) => Some(format!(
                 r#"<td></td><td>
                     <pre>{0} (<a href="{1}">download</a>)</pre>
                     <audio controls><source src="{1}">Your browser does not support audio element.</audio>
                 </td></tr>"#,
                 html_escape::encode_text(&self.label),
-                self.to_href().unwrap(),
+                href,
+            )),
+            (GopherItem::MovieFile, Some(href)) if self.video_type().is_some() => Some(format!(
+                r#"<td></td><td>
+                    <pre>{0} (<a href="{1}">download</a>)</pre>
+                    <video controls preload="metadata"><source src="{1}" type="{2}">Your browser does not support video element.</video>
+                </td>"#,
+                html_escape::encode_text(&self.label),
+                href,
+                self.video_type().unwrap(),
             )),
-            GopherItem::FullTextSearch => Some(format!(
+            (GopherItem::FullTextSearch, _) => Some(format!(
                 r#"<td><i class="fa fa-search"></i></td>
                     <td><form action="/" method="get">
                         <input name="query"  placeholder="{}" type="text">
@@ -362,17 +519,36 @@ impl DirEntry {
                         <input type="hidden" name="t" value="{}">
                         <input type="submit" value="Submit">
                     </form></td><tr>"#,
-                html_escape::encode_text(&self.label),
-                self.url.as_ref().unwrap().to_string(),
-                Into::<char>::into(self.item_type.clone()),
+                html_escape::encode_double_quoted_attribute(&self.label),
+                html_escape::encode_double_quoted_attribute(
+                    &self.url.as_ref().unwrap().to_string()
+                ),
+                Into::<char>::into(self.item_type),
+            )),
+            (GopherItem::UuencodeFile | GopherItem::BinHex, Some(href)) => Some(format!(
+                r#"<td><i class="fa fa-file-archive-o"></i></td><td>{}<pre>(<a href="{}&decode=1">decoded</a>)</pre></td>"#,
+                self.format_label(),
+                href,
             )),
-            GopherItem::ImageFile
-            | GopherItem::BitmapFile
-            | GopherItem::GifFile
-            | GopherItem::PngFile => Some(format!(
-                "<td></td><td><img src=\"{}\" />\n</tr>",
-                self.to_href().unwrap()
+            (GopherItem::Nameserver, _) => Some(format!(
+                r#"<td><i class="fa fa-address-book-o"></i></td>
+                    <td><form action="/" method="get">
+                        <input name="query" placeholder="{}" type="text">
+                        <input type="hidden" name="url" value="{}">
+                        <input type="submit" value="Look up">
+                    </form></td>"#,
+                html_escape::encode_double_quoted_attribute(&self.label),
+                html_escape::encode_double_quoted_attribute(
+                    &self.url.as_ref().unwrap().to_string()
+                ),
             )),
+            (
+                GopherItem::ImageFile
+                | GopherItem::BitmapFile
+                | GopherItem::GifFile
+                | GopherItem::PngFile,
+                Some(href),
+            ) => Some(format!("<td></td><td><img src=\"{}\" />\n</tr>", href)),
             _ => Some(format!(
                 "<td><i class=\"fa fa-file-o\"></i></td><td>{}</td>",
                 self.format_label()
@@ -381,62 +557,524 @@ impl DirEntry {
     }
 }
 
+/// Alternate representation of Gopher+ item.
+#[derive(Debug, Clone, PartialEq)]
+pub struct View {
+    /// MIME type optionally followed by language, e.g. `application/pdf En_US`
+    pub view: String,
+    pub size: Option<String>,
+}
+
+/// Gopher+ item attributes, as returned for `!` request.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Attributes {
+    pub admin: Option<String>,
+    pub mod_date: Option<String>,
+    pub views: Vec<View>,
+    pub abstract_text: Option<String>,
+    pub ask: Vec<AskField>,
+}
+
+impl Attributes {
+    pub fn parse(text: &str) -> Self {
+        let mut attrs = Self::default();
+        let mut block = String::new();
+        for line in text.lines() {
+            if line == "." {
+                break;
+            }
+            if let Some(name) = line.strip_prefix('+') {
+                // block header, like `+VIEWS:`; `+INFO:` has the menu line itself
+                block = name.split(':').next().unwrap_or_default().to_uppercase();
+                continue;
+            }
+            let line = line.strip_prefix(' ').unwrap_or(line);
+            match block.as_str() {
+                "ADMIN" => {
+                    if let Some(admin) = line.strip_prefix("Admin:") {
+                        attrs.admin = Some(String::from(admin.trim()));
+                    } else if let Some(date) = line.strip_prefix("Mod-Date:") {
+                        // human readable date is followed by <YYYYMMDDhhmmss>
+                        let date = date.split('<').next().unwrap_or_default().trim();
+                        attrs.mod_date = Some(String::from(date));
+                    }
+                }
+                "VIEWS" => {
+                    if let Some((view, size)) = line.split_once(':') {
+                        let size = size.trim().trim_start_matches('<').trim_end_matches('>');
+                        attrs.views.push(View {
+                            view: String::from(view.trim()),
+                            size: (!size.is_empty()).then(|| String::from(size)),
+                        });
+                    }
+                }
+                "ASK" => {
+                    if let Some(field) = AskField::parse(line) {
+                        attrs.ask.push(field);
+                    }
+                }
+                "ABSTRACT" => {
+                    let text = attrs.abstract_text.get_or_insert_with(String::new);
+                    if !text.is_empty() {
+                        text.push('\n');
+                    }
+                    text.push_str(line);
+                }
+                _ => {}
+            }
+        }
+        attrs
+    }
+
+    /// Renders abstract, admin info and alternate views of item at `url`, if any.
+    pub fn to_html(&self, url: &GopherURL) -> String {
+        let mut result = String::new();
+        if let Some(text) = &self.abstract_text {
+            result.push_str(&format!(
+                "<pre class=\"abstract\">{}</pre>",
+                html_escape::encode_text(text)
+            ));
+        }
+        let meta: Vec<String> = [
+            self.admin.as_ref().map(|a| format!("admin: {}", a)),
+            self.mod_date.as_ref().map(|d| format!("modified: {}", d)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !meta.is_empty() {
+            result.push_str(&format!(
+                "<div class=\"meta\">{}</div>",
+                html_escape::encode_text(&meta.join(", "))
+            ));
+        }
+        // single view is the item itself
+        if self.views.len() > 1 {
+            let links: Vec<String> = self
+                .views
+                .iter()
+                .filter_map(|v| {
+                    let href = url.view(&v.view).to_href().ok()?;
+                    Some(format!(
+                        "<a href=\"{}\">{}</a>{}",
+                        html_escape::encode_double_quoted_attribute(&href),
+                        html_escape::encode_text(&v.view),
+                        v.size
+                            .as_ref()
+                            .map(|s| format!(" ({})", html_escape::encode_text(s)))
+                            .unwrap_or_default(),
+                    ))
+                })
+                .collect();
+            result.push_str(&format!(
+                "<div class=\"meta\">views: {}</div>",
+                links.join(" | ")
+            ));
+        }
+        result
+    }
+}
+
+/// Question of Gopher+ ASK block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AskField {
+    Ask {
+        prompt: String,
+        default: String,
+    },
+    /// Password, should not be echoed
+    AskP {
+        prompt: String,
+        default: String,
+    },
+    /// Multi-line text
+    AskL {
+        prompt: String,
+        default: String,
+    },
+    /// Yes/no checkbox
+    Select {
+        prompt: String,
+        default: bool,
+    },
+    /// One of the choices
+    Choose {
+        prompt: String,
+        choices: Vec<String>,
+    },
+}
+
+impl AskField {
+    /// Parses `Ask: prompt<TAB>default` like line, returns None for unsupported kinds.
+    fn parse(line: &str) -> Option<Self> {
+        let (kind, rest) = line.split_once(':')?;
+        let mut args = rest.trim_start().split('\t');
+        let prompt = String::from(args.next().unwrap_or_default());
+        let default = String::from(args.next().unwrap_or_default());
+        match kind.trim() {
+            "Ask" => Some(Self::Ask { prompt, default }),
+            "AskP" => Some(Self::AskP { prompt, default }),
+            "AskL" => Some(Self::AskL { prompt, default }),
+            "Select" => Some(Self::Select {
+                prompt,
+                default: default.trim() == "1",
+            }),
+            "Choose" => Some(Self::Choose {
+                choices: std::iter::once(default)
+                    .chain(args.map(String::from))
+                    .filter(|c| !c.is_empty())
+                    .collect(),
+                prompt,
+            }),
+            _ => None,
+        }
+    }
+
+    fn prompt(&self) -> &str {
+        match self {
+            Self::Ask { prompt, .. }
+            | Self::AskP { prompt, .. }
+            | Self::AskL { prompt, .. }
+            | Self::Select { prompt, .. }
+            | Self::Choose { prompt, .. } => prompt,
+        }
+    }
+
+    fn format_input(&self, name: &str) -> String {
+        let attr = |s: &str| html_escape::encode_double_quoted_attribute(s).into_owned();
+        match self {
+            Self::Ask { default, .. } => format!(
+                r#"<input name="{}" id="{0}" type="text" value="{}">"#,
+                name,
+                attr(default)
+            ),
+            Self::AskP { default, .. } => format!(
+                r#"<input name="{}" id="{0}" type="password" value="{}">"#,
+                name,
+                attr(default)
+            ),
+            Self::AskL { default, .. } => format!(
+                r#"<textarea name="{}" id="{0}" rows="5" cols="40">{}</textarea>"#,
+                name,
+                html_escape::encode_text(default)
+            ),
+            Self::Select { default, .. } => format!(
+                r#"<input name="{}" id="{0}" type="checkbox" value="1"{}>"#,
+                name,
+                if *default { " checked" } else { "" }
+            ),
+            Self::Choose { choices, .. } => format!(
+                r#"<select name="{}" id="{0}">{}</select>"#,
+                name,
+                choices
+                    .iter()
+                    .map(|c| format!("<option>{}</option>", html_escape::encode_text(c)))
+                    .collect::<String>()
+            ),
+        }
+    }
+}
+
+/// Renders ASK block as HTML form posting answers back to proxy.
+pub fn ask_form(url: &GopherURL, fields: &[AskField]) -> String {
+    let mut form = String::from("<form action=\"/\" method=\"post\">\n<table>\n");
+    for (n, field) in fields.iter().enumerate() {
+        let name = format!("a{}", n);
+        form.push_str(&format!(
+            "<tr><td><label for=\"{}\">{}</label></td><td>{}</td></tr>\n",
+            name,
+            html_escape::encode_text(field.prompt()),
+            field.format_input(&name)
+        ));
+    }
+    form.push_str(&format!(
+        r#"</table>
+        <input type="hidden" name="url" value="{}">
+        <input type="submit" value="Submit">
+        </form>"#,
+        html_escape::encode_double_quoted_attribute(&url.to_string())
+    ));
+    form
+}
+
+/// Builds Gopher+ data block with answers taken from submitted form, one per line.
+/// Multi-line answers are preceded by their line count.
+fn ask_answers(fields: &[AskField], form: &HashMap<String, String>) -> String {
+    let mut data = String::from("+-1\r\n");
+    for (n, field) in fields.iter().enumerate() {
+        let answer = form.get(&format!("a{}", n)).map_or("", String::as_str);
+        match field {
+            AskField::AskL { .. } => {
+                let lines: Vec<&str> = answer.lines().collect();
+                data.push_str(&format!("{}\r\n", lines.len()));
+                for line in lines {
+                    data.push_str(&format!("{}\r\n", line));
+                }
+            }
+            AskField::Select { .. } => data.push_str(if answer == "1" { "1\r\n" } else { "0\r\n" }),
+            _ => data.push_str(&format!("{}\r\n", answer.replace(['\r', '\n'], " "))),
+        }
+    }
+    data.push_str(".\r\n");
+    data
+}
+
 pub struct Menu {
     pub items: Vec<DirEntry>,
 }
 
 impl Menu {
-    pub async fn from_url(url: &GopherURL, query: Option<String>) -> Result<Self, anyhow::Error> {
+    pub async fn from_url(
+        url: &GopherURL,
+        query: Option<String>,
+        tls: &TlsConfig,
+        options: &FetchOptions,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(
+            Self::from_reader(fetch_url(url, query, tls, options).await?)
+                .await?
+                .inherit_tls(url),
+        )
+    }
+
+    /// Items pointing to the same server as the TLS menu itself are fetched over TLS as well.
+    pub fn inherit_tls(mut self, base: &GopherURL) -> Self {
+        for item in self.items.iter_mut() {
+            item.inherit_tls(base);
+        }
+        self
+    }
+
+    /// Parses menu from already fetched response, e.g. one taken from cache.
+    pub async fn from_reader(mut reader: impl BufReadExt + Unpin) -> Result<Self, anyhow::Error> {
         let mut items: Vec<DirEntry> = Vec::new();
-        let mut response = fetch_url(&url, query).await?.lines();
-        while let Some(Ok(line)) = response.next().await {
+        let mut parser = MenuParser::default();
+        let mut buf = Vec::new();
+        while let Some(line) = read_line(&mut reader, &mut buf).await? {
             if line == "." {
                 break;
             }
-            let entry = DirEntry::from(line.as_str());
-            match entry.item_type {
-                GopherItem::Unknown => continue,
-                GopherItem::Info => {
-                    if let Some(item) = items.last_mut() {
-                        // merge subsequent info items into one paragraph
-                        // to preserve whatever pseudographic may be there
-                        if item.item_type == GopherItem::Info {
-                            item.label.push_str(format!("\n{}", entry.label).as_str());
-                            continue;
-                        }
-                    }
-                    items.push(entry)
+            items.extend(parser.push(&line));
+        }
+        items.extend(parser.finish());
+
+        Ok(Self { items })
+    }
+}
+
+/// Reads next line of response without line terminator.
+/// Unlike `lines()`, it decodes non-UTF-8 text lossily, as old servers often use legacy
+/// encodings, and does not choke on I/O errors in the middle of a line.
+pub async fn read_line(
+    reader: &mut (impl BufReadExt + Unpin),
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<String>> {
+    buf.clear();
+    if reader.read_until(b'\n', buf).await? == 0 {
+        return Ok(None);
+    }
+    let line = buf.strip_suffix(b"\n").unwrap_or(buf);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Ok(Some(String::from_utf8_lossy(line).into_owned()))
+}
+
+/// Parses menu line by line, so that it can be rendered while still being fetched.
+///
+/// Item is held back until the next one arrives, since lines that follow may still
+/// belong to it: subsequent info items are merged into one paragraph to preserve
+/// whatever pseudographic may be there, and mirrors are attached to the item above them.
+#[derive(Debug, Default)]
+pub struct MenuParser {
+    pending: Option<DirEntry>,
+}
+
+impl MenuParser {
+    /// Feeds next line of menu, returning previous item if it is complete.
+    pub fn push(&mut self, line: &str) -> Option<DirEntry> {
+        let entry = DirEntry::from(line);
+        match entry.item_type {
+            GopherItem::Unknown => None,
+            GopherItem::Mirror => {
+                let primary = self.pending.as_mut().and_then(|i| i.url.as_mut());
+                if let (Some(primary), Some(mirror)) = (primary, entry.url) {
+                    primary.mirrors.push(GopherURL {
+                        gopher_type: primary.gopher_type,
+                        ..mirror
+                    });
                 }
-                _ => items.push(entry),
+                None
             }
+            GopherItem::Info => match self.pending.as_mut() {
+                Some(item) if item.item_type == GopherItem::Info => {
+                    item.label.push_str(format!("\n{}", entry.label).as_str());
+                    None
+                }
+                _ => self.pending.replace(entry),
+            },
+            _ => self.pending.replace(entry),
         }
+    }
 
-        Ok(Self { items: items })
+    /// Returns the last item once menu is over.
+    pub fn finish(self) -> Option<DirEntry> {
+        self.pending
     }
 }
 
+trait Connection: Read + Write + Unpin + Send + Sync {}
+impl<T: Read + Write + Unpin + Send + Sync> Connection for T {}
+
+async fn connect(
+    url: &GopherURL,
+    tls: &TlsConfig,
+    policy: &Policy,
+) -> Result<Box<dyn Connection>, anyhow::Error> {
+    let addr = format!("{}:{}", url.host, url.port);
+    if url.tls {
+        let stream = policy.connect(&url.host, url.port).await?;
+        return Ok(Box::new(tls.handshake(stream, &url.host, url.port).await?));
+    }
+    if tls.opportunistic {
+        let stream = policy.connect(&url.host, url.port).await?;
+        let handshake = tls.handshake(stream, &url.host, url.port);
+        match io::timeout(OPPORTUNISTIC_TLS_TIMEOUT, handshake).await {
+            Ok(stream) => return Ok(Box::new(stream)),
+            // do not let anyone downgrade connection by presenting wrong certificate
+            Err(e) if tls::is_certificate_changed(&e) => return Err(e.into()),
+            Err(e) => log::debug!("no TLS on {}, falling back to plain TCP: {}", addr, e),
+        }
+    }
+    Ok(Box::new(policy.connect(&url.host, url.port).await?))
+}
+
+async fn connect_limited(
+    url: &GopherURL,
+    tls: &TlsConfig,
+    options: &FetchOptions,
+    started: Instant,
+) -> Result<Box<dyn Connection>, anyhow::Error> {
+    let connect = connect(url, tls, &options.policy);
+    Ok(Box::new(
+        limits::connect_limited(&url.host, connect, options, started).await?,
+    ))
+}
+
 pub async fn fetch_url(
     url: &GopherURL,
     query: Option<String>,
+    tls: &TlsConfig,
+    options: &FetchOptions,
 ) -> Result<impl BufReadExt, anyhow::Error> {
-    let mut stream = TcpStream::connect(format!("{}:{}", url.host, url.port,)).await?;
-    let selector = match urlencoding::decode(
-        match query {
-            Some(q) => format!("{}\t{}\r\n", url.selector, q),
-            None => format!("{}\r\n", url.selector),
-        }
-        .as_str(),
-    ) {
-        Ok(s) => s.into_owned(),
-        Err(e) => {
-            return Err(anyhow!("decoding URL: {}", e));
+    Ok(fetch_mirrored(url, query, tls, options).await?.0)
+}
+
+/// Fetches URL, trying its mirrors in order if server can't be connected to.
+/// Returns response along with the mirror that served it, if it was not the primary server.
+pub async fn fetch_mirrored(
+    url: &GopherURL,
+    query: Option<String>,
+    tls: &TlsConfig,
+    options: &FetchOptions,
+) -> Result<(impl BufReadExt, Option<GopherURL>), anyhow::Error> {
+    let started = Instant::now();
+    let mut last_error = None;
+    for (n, server) in std::iter::once(url).chain(url.mirrors.iter()).enumerate() {
+        let stream = match connect_limited(server, tls, options, started).await {
+            Ok(stream) => stream,
+            Err(e) if e.downcast_ref().is_some_and(tls::is_certificate_changed) => return Err(e),
+            // server is not down, mirrors may still be tried
+            Err(e) if e.is::<Throttled>() => {
+                last_error = Some(e);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("failed to connect to {}: {}", server, e);
+                METRICS.upstream_errors.inc(&[&server.host]);
+                last_error = Some(e);
+                continue;
+            }
+        };
+        let mut request = server.selector.clone();
+        if let Some(q) = &query {
+            request.push('\t');
+            request.push_str(q);
+        }
+        if let Some(plus) = &url.plus {
+            request.push('\t');
+            request.push_str(plus);
         }
+        request.push_str("\r\n");
+        let request = decode_request(&request)?;
+        let response = match send_request(stream, server, &request, url.plus.is_some()).await {
+            Ok(response) => response,
+            Err(e) => {
+                METRICS.upstream_errors.inc(&[&server.host]);
+                return Err(e);
+            }
+        };
+        METRICS
+            .fetch_latency
+            .observe(&[&url.gopher_type.to_string()], started.elapsed());
+        return Ok((response, (n > 0).then(|| server.clone())));
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("no servers to connect to")))
+}
+
+/// Sends answers to item's Gopher+ ASK block, returning server response.
+pub async fn submit_ask(
+    url: &GopherURL,
+    fields: &[AskField],
+    form: &HashMap<String, String>,
+    tls: &TlsConfig,
+    options: &FetchOptions,
+) -> Result<impl BufReadExt, anyhow::Error> {
+    let mut request = decode_request(&format!("{}\t+\t1\r\n", url.selector))?;
+    request.push_str(&ask_answers(fields, form));
+    let stream = connect_limited(url, tls, options, Instant::now()).await?;
+    // responses to ASK are not cached, so truncation is tolerated
+    send_request(Box::new(Lenient(stream)), url, &request, true).await
+}
+
+/// Selectors are kept urlencoded in URLs, so they are decoded before sending.
+fn decode_request(request: &str) -> Result<String, anyhow::Error> {
+    let decode = |s: &str| match urlencoding::decode(s) {
+        Ok(s) => Ok(s.into_owned()),
+        Err(e) => Err(anyhow!("decoding URL: {}", e)),
     };
-    stream
-        .write_all(urlencoding::decode(&selector).unwrap().as_bytes())
-        .await?;
+    decode(&decode(request)?)
+}
+
+async fn send_request(
+    mut stream: Box<dyn Connection>,
+    url: &GopherURL,
+    request: &str,
+    plus: bool,
+) -> Result<impl BufReadExt, anyhow::Error> {
+    stream.write_all(request.as_bytes()).await?;
     let mut buf = BufReader::new(stream);
 
+    // Gopher+ response starts with data length line, `--` one means error
+    let mut prefix = Vec::new();
+    if plus {
+        buf.read_until(b'\n', &mut prefix).await?;
+        match prefix.first() {
+            Some(b'+') => prefix.clear(),
+            Some(b'-') if prefix.get(1) == Some(&b'-') => {
+                let mut message = String::new();
+                buf.read_to_string(&mut message).await?;
+                // first line is error code along with administrator contact
+                let message = message
+                    .split_once('\n')
+                    .map_or(message.as_str(), |(_, m)| m);
+                let message = message.trim_end().trim_end_matches('.').trim();
+                log::error!("got gopher+ error fetching {}: {}", url, message);
+                return Err(anyhow!("{}", message));
+            }
+            // not a Gopher+ server after all
+            _ => {}
+        }
+    }
+
     /*
        Since gopher has no way to specify any metadata in its response,
        so instead of actual content there may be a dir entry with error.
@@ -455,10 +1093,12 @@ pub async fn fetch_url(
             _ => {}
         }
     }
-    Ok(Cursor::new(header[0..bytes_read].to_vec()).chain(buf))
+    prefix.extend_from_slice(&header[0..bytes_read]);
+    Ok(Cursor::new(prefix).chain(buf))
 }
 
-fn decode_ansi_style(text: &str) -> String {
+/// Converts ANSI SGR color codes into HTML spans, escaping the text.
+pub fn decode_ansi_style(text: &str) -> String {
     let mut result = String::new();
     let mut span_style: Vec<String> = Vec::new();
     for token in parse_ansi(text) {
@@ -472,7 +1112,7 @@ fn decode_ansi_style(text: &str) -> String {
                         html_escape::encode_text(txt),
                     ))
                 } else {
-                    result.push_str(txt)
+                    result.push_str(&html_escape::encode_text(txt))
                 }
             }
             ElementKind::Sgr => {
@@ -494,13 +1134,23 @@ fn decode_ansi_style(text: &str) -> String {
             _ => {}
         }
     }
-    return result;
+    result
 }
 
 fn to_color(c: AnsiColor) -> String {
     match c {
-        AnsiColor::Bit4(v) | AnsiColor::Bit8(v) => String::from(_ANSI_COLORS[usize::from(v)]),
-        AnsiColor::Bit24 { r, g, b } => format!("rgb({r}, {g}, {b}"),
+        // 4-bit colors come as raw SGR codes, i.e. 30-37/90-97 for foreground, 40-47/100-107 for background
+        AnsiColor::Bit4(v) => String::from(
+            _ANSI_COLORS[usize::from(match v {
+                30..=37 => v - 30,
+                40..=47 => v - 40,
+                90..=97 => v - 90 + 8,
+                100..=107 => v - 100 + 8,
+                v => v % 16,
+            })],
+        ),
+        AnsiColor::Bit8(v) => String::from(_ANSI_COLORS[usize::from(v)]),
+        AnsiColor::Bit24 { r, g, b } => format!("rgb({r}, {g}, {b})"),
     }
 }
 
@@ -521,6 +1171,183 @@ mod tests {
         assert_eq!(url.host, "1.1.1.1");
         assert_eq!(url.selector, "selector");
         assert_eq!(url.gopher_type, GopherItem::TextFile);
+        e = DirEntry::from(
+            "0Finger me	john	example.com	79
+",
+        );
+        assert_eq!(
+            e.to_href().unwrap(),
+            "?url=finger%3A%2F%2Fjohn%40example.com"
+        );
+        e = DirEntry::from(
+            "hFinger URL	URL:finger://jane@example.com	example.com	70
+",
+        );
+        assert_eq!(
+            e.to_href().unwrap(),
+            "?url=finger%3A%2F%2Fjane%40example.com"
+        );
+    }
+
+    #[test]
+    fn url_selectors() {
+        let e = DirEntry::from(
+            "hXSS	URL:javascript:alert(1)	example.com	70
+",
+        );
+        assert_eq!(e.to_href(), None);
+        assert!(!e.format_row().unwrap().contains("href"));
+        let e = DirEntry::from(
+            "IPic	URL:JavaScript:alert(1)	example.com	70
+",
+        );
+        assert!(!e.format_row().unwrap().contains("src"));
+        let e = DirEntry::from(
+            "hWeb	URL:https://example.com/?a=\"><b>	example.com	70
+",
+        );
+        assert!(e
+            .format_row()
+            .unwrap()
+            .contains(r#"href="https://example.com/?a=&quot;&gt;&lt;b&gt;""#));
+    }
+
+    #[test]
+    fn gopher_plus_attributes() {
+        let e = DirEntry::from("0About\t/about\texample.com\t70\t+\r\n");
+        assert!(e.plus);
+        assert!(!DirEntry::from("0About\t/about\texample.com\t70\r\n").plus);
+
+        let attrs = Attributes::parse(
+            "+INFO: 0About\t/about\texample.com\t70\t+\r\n\
+            +ADMIN:\r\n Admin: Jane <jane@example.com>\r\n \
+            Mod-Date: Wed Jul 28 17:02:01 1993 <19930728170201>\r\n\
+            +VIEWS:\r\n text/plain: <10k>\r\n application/pdf En_US: <200k>\r\n\
+            +ABSTRACT:\r\n First line\r\n second line\r\n",
+        );
+        assert_eq!(attrs.admin.as_deref(), Some("Jane <jane@example.com>"));
+        assert_eq!(attrs.mod_date.as_deref(), Some("Wed Jul 28 17:02:01 1993"));
+        assert_eq!(
+            attrs.abstract_text.as_deref(),
+            Some("First line\nsecond line")
+        );
+        assert_eq!(attrs.views.len(), 2);
+        assert_eq!(attrs.views[1].view, "application/pdf En_US");
+        assert_eq!(attrs.views[1].size.as_deref(), Some("200k"));
+
+        assert!(e
+            .format_row()
+            .unwrap()
+            .contains("&amp;info=1\">attributes</a>"));
+        let html = attrs.to_html(e.url.as_ref().unwrap());
+        assert!(html.contains("<pre class=\"abstract\">First line\nsecond line</pre>"));
+        assert!(html.contains(">application/pdf En_US</a> (200k)"));
+
+        let url = e.url.unwrap().view(&attrs.views[1].view);
+        assert_eq!(url.gopher_type, GopherItem::PdfFile);
+        assert_eq!(
+            url.to_string(),
+            "gopher://example.com:70/P/about%09%09%2Bapplication%2Fpdf%20En_US"
+        );
+        assert_eq!(GopherURL::try_from(url.to_string().as_str()).unwrap(), url);
+    }
+
+    #[test]
+    fn gopher_plus_ask() {
+        let e = DirEntry::from("1Search\t/search\texample.com\t70\t?\r\n");
+        assert!(e.ask);
+        assert!(e.to_href().unwrap().ends_with("&ask=1"));
+
+        let attrs = Attributes::parse(
+            "+INFO: 1Search\t/search\texample.com\t70\t?\r\n\
+            +ASK:\r\n Ask: Name?\tanonymous\r\n AskP: Password:\r\n \
+            AskL: Comments\r\n Select: Subscribe:\t1\r\n \
+            Choose: Color\tred\tgreen\r\n AskF: File\r\n",
+        );
+        assert_eq!(
+            attrs.ask,
+            vec![
+                AskField::Ask {
+                    prompt: String::from("Name?"),
+                    default: String::from("anonymous")
+                },
+                AskField::AskP {
+                    prompt: String::from("Password:"),
+                    default: String::new()
+                },
+                AskField::AskL {
+                    prompt: String::from("Comments"),
+                    default: String::new()
+                },
+                AskField::Select {
+                    prompt: String::from("Subscribe:"),
+                    default: true
+                },
+                AskField::Choose {
+                    prompt: String::from("Color"),
+                    choices: vec![String::from("red"), String::from("green")]
+                },
+            ]
+        );
+
+        let form = HashMap::from([
+            (String::from("a0"), String::from("jane")),
+            (String::from("a1"), String::from("secret")),
+            (String::from("a2"), String::from("one\r\ntwo")),
+            (String::from("a4"), String::from("green")),
+        ]);
+        assert_eq!(
+            ask_answers(&attrs.ask, &form),
+            "+-1\r\njane\r\nsecret\r\n2\r\none\r\ntwo\r\n0\r\ngreen\r\n.\r\n"
+        );
+    }
+
+    #[async_std::test]
+    async fn mirrors() {
+        let menu = Menu::from_reader(Cursor::new(
+            "0Paper\t/paper.txt\tprimary.org\t70\r\n\
+            +Mirror\t/mirror/paper.txt\tmirror.org\t7070\r\n\
+            +Mirror\t/paper.txt\tbackup.org\t70\r\n\
+            .\r\n"
+                .as_bytes(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(menu.items.len(), 1);
+        let url = menu.items[0].url.as_ref().unwrap();
+        assert_eq!(url.mirrors.len(), 2);
+        assert_eq!(url.mirrors[0].gopher_type, GopherItem::TextFile);
+        assert_eq!(
+            url.mirrors[0].to_string(),
+            "gopher://mirror.org:7070/0/mirror/paper.txt"
+        );
+        assert_eq!(
+            url.to_href().unwrap(),
+            "?url=gopher%3A%2F%2Fprimary.org%3A70%2F0%2Fpaper.txt\
+            &mirrors[0]=gopher%3A%2F%2Fmirror.org%3A7070%2F0%2Fmirror%2Fpaper.txt\
+            &mirrors[1]=gopher%3A%2F%2Fbackup.org%3A70%2F0%2Fpaper.txt"
+        );
+    }
+
+    #[test]
+    fn incremental_parsing() {
+        let mut parser = MenuParser::default();
+        assert!(parser.push("i /\\_/\\\tfake\t(NULL)\t0").is_none());
+        assert!(parser.push("i( o.o )\tfake\t(NULL)\t0").is_none());
+        // art is complete once something else follows it
+        let art = parser.push("1Cats\t/cats\texample.com\t70").unwrap();
+        assert_eq!(art.label, " /\\_/\\\n( o.o )");
+        assert!(parser.push("+Mirror\t/cats\tmirror.org\t70").is_none());
+        let cats = parser.finish().unwrap();
+        assert_eq!(cats.url.unwrap().mirrors.len(), 1);
+    }
+
+    #[test]
+    fn ansi_colors() {
+        assert_eq!(
+            decode_ansi_style("\x1b[31mred\x1b[0m <b>\x1b[38;5;196mbright\x1b[0m"),
+            r#"<span style="color:#800000">red</span> &lt;b&gt;<span style="color:#ff0000">bright</span>"#
+        );
     }
 
     #[test]
@@ -546,5 +1373,12 @@ mod tests {
 
         u = GopherURL::new("1.1.1.1", "70", &GopherItem::TextFile, "some-selector");
         assert_eq!(u.to_string(), "gopher://1.1.1.1:70/0some-selector");
+        assert!(!u.tls);
+
+        u = GopherURL::try_from("gophers://example.com:7070/1/phlog").unwrap();
+        assert!(u.tls);
+        assert_eq!(u.port, 7070);
+        assert_eq!(u.selector, "/phlog");
+        assert_eq!(u.to_string(), "gophers://example.com:7070/1/phlog");
     }
 }
@@ -0,0 +1,294 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+use async_std::io::{self, Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::stream::StreamExt as _;
+use async_std::task;
+use futures_rustls::pki_types::pem::PemObject;
+use futures_rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use futures_rustls::rustls::crypto::{ring, CryptoProvider};
+use futures_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use futures_rustls::rustls::sign::CertifiedKey;
+use futures_rustls::rustls::ServerConfig;
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use tide::listener::{ListenInfo, Listener, ToListener};
+use tide::{log, Server};
+
+/// Time client has to complete TLS handshake in.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate chain and key served over HTTPS, loaded again when their files change.
+#[derive(Debug)]
+pub struct Certificates {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of files current key pair was loaded from
+    loaded: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl Certificates {
+    pub fn load(
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Result<Self, anyhow::Error> {
+        let (cert_path, key_path) = (cert_path.into(), key_path.into());
+        let provider = Arc::new(ring::default_provider());
+        let loaded = (modified(&cert_path)?, modified(&key_path)?);
+        let current = load_key_pair(&cert_path, &key_path, &provider)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// Loads key pair again if either file was modified since it was loaded.
+    /// On failure previous key pair stays in use, and loading is tried again next time.
+    pub fn reload_if_changed(&self) -> Result<bool, anyhow::Error> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let modified = (modified(&self.cert_path)?, modified(&self.key_path)?);
+        if modified == *loaded {
+            return Ok(false);
+        }
+        let key_pair = load_key_pair(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key_pair);
+        *loaded = modified;
+        Ok(true)
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor, anyhow::Error> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime, anyhow::Error> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .with_context(|| format!("reading {}", path.display()))
+}
+
+/// Loads PEM files, making sure key matches certificate, as files may be caught half-way
+/// through renewal.
+fn load_key_pair(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, anyhow::Error> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", cert_path.display()))?;
+    if chain.is_empty() {
+        return Err(anyhow!("no certificates in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("reading private key from {}", key_path.display()))?;
+    CertifiedKey::from_der(chain, key, provider).with_context(|| {
+        format!(
+            "loading key pair from {} and {}",
+            cert_path.display(),
+            key_path.display()
+        )
+    })
+}
+
+/// Listener serving HTTPS on a bound TCP socket.
+pub struct TlsListener<State> {
+    listener: Option<TcpListener>,
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    server: Option<Server<State>>,
+}
+
+impl<State> TlsListener<State> {
+    pub fn new(listener: std::net::TcpListener, acceptor: TlsAcceptor) -> io::Result<Self> {
+        Ok(Self {
+            addr: listener.local_addr()?,
+            listener: Some(listener.into()),
+            acceptor,
+            server: None,
+        })
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for TlsListener<State> {
+    async fn bind(&mut self, server: Server<State>) -> io::Result<()> {
+        self.server = Some(server);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let server = self
+            .server
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+        let listener = self
+            .listener
+            .take()
+            .expect("`Listener::accept` should only be called once");
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    task::spawn(serve(server.clone(), self.acceptor.clone(), stream));
+                }
+                // mostly running out of descriptors, which may get better
+                Err(e) => {
+                    log::error!("failed to accept connection: {}", e);
+                    task::sleep(Duration::from_millis(500)).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        vec![ListenInfo::new(self.to_string(), String::from("tcp"), true)]
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for TlsListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self> {
+        Ok(self)
+    }
+}
+
+async fn serve<State: Clone + Send + Sync + 'static>(
+    server: Server<State>,
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+) {
+    let local_addr = stream.local_addr().ok().map(|a| a.to_string());
+    let peer_addr = stream.peer_addr().ok().map(|a| a.to_string());
+    let stream = match io::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("TLS handshake with {:?} failed: {}", peer_addr, e);
+            return;
+        }
+    };
+    let result = async_h1::accept(Shared(Arc::new(Mutex::new(stream))), |mut req| async {
+        req.set_local_addr(local_addr.as_ref());
+        req.set_peer_addr(peer_addr.as_ref());
+        server.respond(req).await
+    })
+    .await;
+    if let Err(e) = result {
+        log::debug!("HTTPS connection with {:?} failed: {}", peer_addr, e);
+    }
+}
+
+impl<State> fmt::Debug for TlsListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl<State> fmt::Display for TlsListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}", self.addr)
+    }
+}
+
+/// TLS connection shared between reading and writing sides, as async-h1 wants to clone it.
+#[derive(Clone)]
+struct Shared(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl Read for Shared {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl Write for Shared {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+    }
+}
+
+/// URL to redirect plain HTTP request for `host` to, keeping its path and query.
+/// Port, if any, is replaced by `https_port`.
+pub fn redirect_url(host: &str, path_and_query: &str, https_port: u16) -> Option<String> {
+    let host = match host.rsplit_once(':') {
+        // brackets of IPv6 address hold colons too
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    if host.is_empty() || host.contains(['/', '@', '?', '#']) {
+        return None;
+    }
+    Some(match https_port {
+        443 => format!("https://{}{}", host, path_and_query),
+        port => format!("https://{}:{}{}", host, port, path_and_query),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect() {
+        assert_eq!(
+            redirect_url("example.org", "/?url=gopher%3A%2F%2Fsdf.org", 443).as_deref(),
+            Some("https://example.org/?url=gopher%3A%2F%2Fsdf.org")
+        );
+        assert_eq!(
+            redirect_url("example.org:8080", "/static/style.css", 8443).as_deref(),
+            Some("https://example.org:8443/static/style.css")
+        );
+        assert_eq!(
+            redirect_url("[::1]", "/", 443).as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(
+            redirect_url("[::1]:80", "/", 443).as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(redirect_url("evil.org/x", "/", 443), None);
+        assert_eq!(redirect_url("", "/", 443), None);
+    }
+}
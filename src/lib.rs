@@ -3,4 +3,26 @@
 //! Simple and clean HTTP proxy for browsing gopherspace via your browser.
 //! Supports ANSI color codes, image and other media inlining in directory view.
 
+pub mod body;
+pub mod cache;
+pub mod config;
+pub mod cso;
+pub mod decode;
+pub mod finger;
+pub mod forwarded;
+pub mod gemini;
 pub mod gopher;
+pub mod https;
+pub mod limits;
+pub mod listen;
+pub mod metrics;
+pub mod policy;
+pub mod range;
+pub mod ratelimit;
+pub mod shutdown;
+pub mod sniff;
+pub mod spool;
+pub mod store;
+pub mod telnet;
+pub mod tls;
+pub mod websocket;
@@ -0,0 +1,256 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+use async_std::io::{self, Read, Write};
+use tide::log;
+
+use crate::metrics::METRICS;
+use crate::policy::Policy;
+use crate::ratelimit::RateLimit;
+
+/// Limits applied when fetching from upstream servers,
+/// so that blackholed hosts and endless responses won't hold requests forever.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Time to connect to server, including TLS handshake
+    pub connect_timeout: Duration,
+    /// Time server may stay silent while sending response
+    pub read_timeout: Duration,
+    /// Time whole response has to be received in, counting from connection attempt
+    pub deadline: Duration,
+    /// Longest response accepted, bytes
+    pub max_bytes: u64,
+    /// Servers that may be connected to
+    pub policy: Arc<Policy>,
+    /// Connections made to each server, so that clients can't hammer it through proxy
+    pub upstream_limit: Arc<RateLimit<String>>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            deadline: Duration::from_secs(600),
+            max_bytes: 1024 * 1024 * 1024,
+            policy: Arc::default(),
+            upstream_limit: Arc::new(RateLimit::new(0.0, 0)),
+        }
+    }
+}
+
+/// Fetch limit that was hit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchError {
+    ConnectTimeout(Duration),
+    ReadTimeout(Duration),
+    DeadlineExceeded(Duration),
+    TooLarge(u64),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectTimeout(t) => write!(
+                f,
+                "server did not accept connection within {} seconds",
+                t.as_secs()
+            ),
+            Self::ReadTimeout(t) => {
+                write!(f, "server sent nothing for {} seconds", t.as_secs())
+            }
+            Self::DeadlineExceeded(t) => write!(
+                f,
+                "response was not received completely within {} seconds",
+                t.as_secs()
+            ),
+            Self::TooLarge(max) => write!(f, "response is bigger than {} bytes", max),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<FetchError> for io::Error {
+    fn from(e: FetchError) -> Self {
+        let kind = match e {
+            FetchError::TooLarge(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::TimedOut,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Connection to server refused because of its rate limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Throttled {
+    pub host: String,
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many requests to {}, try again later", self.host)
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+/// Connects to `host` within connect timeout, limiting what is read from it afterwards.
+/// `connect` includes TLS handshake, if any, and deadline is counted from `started`.
+/// Each connection takes a token from rate limit of the host.
+pub async fn connect_limited<S>(
+    host: &str,
+    connect: impl Future<Output = Result<S, anyhow::Error>>,
+    options: &FetchOptions,
+    started: Instant,
+) -> Result<Limited<S>, anyhow::Error> {
+    if let Err(retry_after) = options.upstream_limit.take(host.to_lowercase()) {
+        log::warn!("rate limit of {} exceeded", host);
+        METRICS.rate_limited.inc(&["upstream"]);
+        return Err(Throttled {
+            host: String::from(host),
+            retry_after,
+        }
+        .into());
+    }
+    match async_std::future::timeout(options.connect_timeout, connect).await {
+        Ok(stream) => Ok(Limited::new(stream?, options, started)),
+        Err(_) => Err(FetchError::ConnectTimeout(options.connect_timeout).into()),
+    }
+}
+
+/// Connection to upstream server enforcing read limits of [`FetchOptions`].
+pub struct Limited<S> {
+    inner: S,
+    options: FetchOptions,
+    read: u64,
+    idle: Timer,
+    deadline: Timer,
+}
+
+impl<S> Limited<S> {
+    /// Wraps connection, deadline is counted from `started`.
+    pub fn new(inner: S, options: &FetchOptions, started: Instant) -> Self {
+        Self {
+            inner,
+            options: options.clone(),
+            read: 0,
+            idle: Timer::after(options.read_timeout),
+            deadline: Timer::at(started + options.deadline),
+        }
+    }
+}
+
+impl<S: Read + Unpin> Read for Limited<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if Pin::new(&mut this.deadline).poll(cx).is_ready() {
+            return Poll::Ready(Err(
+                FetchError::DeadlineExceeded(this.options.deadline).into()
+            ));
+        }
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                this.read += n as u64;
+                METRICS.proxied_bytes.add(&[], n as u64);
+                if this.read > this.options.max_bytes {
+                    return Poll::Ready(Err(FetchError::TooLarge(this.options.max_bytes).into()));
+                }
+                this.idle.set_after(this.options.read_timeout);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Pending if Pin::new(&mut this.idle).poll(cx).is_ready() => Poll::Ready(Err(
+                FetchError::ReadTimeout(this.options.read_timeout).into(),
+            )),
+            other => other,
+        }
+    }
+}
+
+impl<S: Write + Unpin> Write for Limited<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{Cursor, ReadExt};
+
+    /// Sends its data, then stays silent forever.
+    struct Stalled(Cursor<Vec<u8>>);
+
+    impl Read for Stalled {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match Pin::new(&mut self.get_mut().0).poll_read(cx, buf) {
+                Poll::Ready(Ok(0)) => Poll::Pending,
+                other => other,
+            }
+        }
+    }
+
+    fn error(result: io::Result<usize>) -> FetchError {
+        let e = result.unwrap_err();
+        e.into_inner()
+            .unwrap()
+            .downcast_ref::<FetchError>()
+            .unwrap()
+            .clone()
+    }
+
+    #[async_std::test]
+    async fn limits() {
+        let options = FetchOptions {
+            read_timeout: Duration::from_millis(50),
+            deadline: Duration::from_millis(200),
+            max_bytes: 10,
+            ..FetchOptions::default()
+        };
+        let mut buf = Vec::new();
+
+        let mut stream = Limited::new(Cursor::new(vec![0; 11]), &options, Instant::now());
+        assert_eq!(
+            error(stream.read_to_end(&mut buf).await),
+            FetchError::TooLarge(10)
+        );
+
+        let mut stream = Limited::new(Stalled(Cursor::new(vec![0; 5])), &options, Instant::now());
+        assert_eq!(
+            error(stream.read_to_end(&mut buf).await),
+            FetchError::ReadTimeout(options.read_timeout)
+        );
+
+        let started = Instant::now() - options.deadline;
+        let mut stream = Limited::new(Cursor::new(vec![0; 5]), &options, started);
+        assert_eq!(
+            error(stream.read_to_end(&mut buf).await),
+            FetchError::DeadlineExceeded(options.deadline)
+        );
+    }
+}
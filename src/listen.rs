@@ -0,0 +1,127 @@
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+
+/// First descriptor passed by systemd, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Address to accept HTTP connections on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    /// `HOST:PORT`, e.g. `localhost:8080` or `[::]:8080`
+    Tcp(String),
+    /// `unix:PATH`
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(anyhow!("no socket path in {}", s)),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None if s.rsplit_once(':').is_some() => Ok(Self::Tcp(String::from(s))),
+            None => Err(anyhow!("expected HOST:PORT or unix:PATH, got {}", s)),
+        }
+    }
+}
+
+/// Removes socket left behind by previous run, so that its path can be bound again.
+/// Anything other than a socket is left alone for bind to fail on.
+pub fn remove_stale_socket(path: &Path) -> Result<(), anyhow::Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("removing stale socket {}", path.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Socket inherited from service manager.
+pub enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes listening sockets passed by systemd socket activation, if any.
+/// Environment is cleared so that they are taken only once.
+pub fn inherited() -> Result<Vec<Inherited>, anyhow::Error> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let Some(count) = listen_fds(pid.as_deref(), fds.as_deref(), std::process::id())? else {
+        return Ok(Vec::new());
+    };
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors over to us and nothing else owns them
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            // address of Unix socket is not an IP one, which is the way to tell them apart
+            match tcp.local_addr() {
+                Ok(_) => Ok(Inherited::Tcp(tcp)),
+                Err(_) => {
+                    let fd = tcp.into_raw_fd();
+                    // SAFETY: same descriptor, given back by TcpListener
+                    let unix = unsafe { UnixListener::from_raw_fd(fd) };
+                    unix.local_addr()
+                        .with_context(|| format!("inherited descriptor {} is not a socket", fd))?;
+                    Ok(Inherited::Unix(unix))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Number of descriptors passed to process `own_pid`, given values of `LISTEN_PID`
+/// and `LISTEN_FDS`.
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    own_pid: u32,
+) -> Result<Option<RawFd>, anyhow::Error> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    // variables meant for some other process we've inherited them from
+    if pid.parse::<u32>().ok() != Some(own_pid) {
+        return Ok(None);
+    }
+    let count = fds
+        .parse::<RawFd>()
+        .ok()
+        .filter(|n| *n >= 0)
+        .ok_or_else(|| anyhow!("invalid LISTEN_FDS: {}", fds))?;
+    Ok(Some(count).filter(|n| *n > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            "[::]:8080".parse::<Listen>().unwrap(),
+            Listen::Tcp(String::from("[::]:8080"))
+        );
+        assert_eq!(
+            "unix:/run/proxy70.sock".parse::<Listen>().unwrap(),
+            Listen::Unix(PathBuf::from("/run/proxy70.sock"))
+        );
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), Some(2));
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), None);
+        assert_eq!(listen_fds(Some("42"), Some("0"), 42).unwrap(), None);
+        assert_eq!(listen_fds(None, None, 42).unwrap(), None);
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+    }
+}
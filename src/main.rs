@@ -1,55 +1,520 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
-use async_std::io::prelude::BufReadExt as _;
+use anyhow::{anyhow, Context as _, Result};
+use async_signal::{Signal, Signals};
+use async_std::channel::Sender;
+use async_std::io::{self, prelude::SeekExt as _, BufRead, BufReader, ReadExt as _, SeekFrom};
+use async_std::net::TcpStream;
 use async_std::stream::StreamExt as _;
 use async_std::task;
-use clap::Parser;
-use dashmap::DashMap;
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use futures_lite::FutureExt as _;
+use proxy70::body;
+use proxy70::cache::{self, Cache, Fetched, NotCached};
+use proxy70::config::{Config, Link};
+use proxy70::cso;
+use proxy70::decode;
+use proxy70::finger::{self, FingerURL};
+use proxy70::forwarded;
+use proxy70::gemini::{self, GeminiURL};
 use proxy70::gopher::{self, GopherItem, GopherURL};
+use proxy70::https::{self, Certificates, TlsListener};
+use proxy70::limits::{FetchOptions, Throttled};
+use proxy70::listen::{self, Inherited, Listen};
+use proxy70::metrics::METRICS;
+use proxy70::policy::{Blocked, Cidr, Policy};
+use proxy70::range;
+use proxy70::ratelimit::RateLimit;
+use proxy70::shutdown::{GuardedBody, Tracker};
+use proxy70::sniff;
+use proxy70::spool::{Spool, Spooled, SpooledFile};
+use proxy70::store::DiskStore;
+use proxy70::telnet;
+use proxy70::tls::{KnownHosts, TlsConfig};
+use proxy70::websocket;
 use serde::Deserialize;
 
-use tide::{http::mime, Request};
+use tide::listener::{ConcurrentListener, Listener as _};
+use tide::{
+    http::{mime, Mime},
+    log, Request,
+};
 use tide::{prelude::*, Body, Middleware, Next, StatusCode};
 use tinytemplate::TinyTemplate;
 
 const _PAGE_HTML: &str = include_str!("../static/page.html");
 const _WELCOME_HTML: &str = include_str!("../static/welcome.html");
+const DEFAULT_LINKS: &str = include_str!("../static/links.toml");
+/// Welcome page is rendered with start page links in place of this.
+const LINKS_PLACEHOLDER: &str = "<!-- links -->";
+/// Page template is rendered with this body and split at it into head and tail.
+const BODY_PLACEHOLDER: &str = "<!-- body -->";
+/// Bytes of file looked at to guess its type.
+const SNIFF_LEN: usize = 512;
+const TELNET_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_LISTEN: &str = "localhost:8080";
+const RATE_LIMIT_EVICT_INTERVAL: Duration = Duration::from_secs(60);
+const CERTIFICATES_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct State {
+    cache: Arc<Cache>,
+    tls: Arc<TlsConfig>,
+    /// Downloads are spooled to disk to be served with length and in ranges, unless disabled
+    spool: Option<Arc<Spool>>,
+    /// Replaced as a whole when configuration is reloaded
+    settings: Arc<RwLock<Arc<Settings>>>,
+    /// Requests in flight, waited for on shutdown
+    shutdown: Tracker,
+    /// Key pair HTTPS is served with, if it is
+    certificates: Option<Arc<Certificates>>,
+}
+
+impl State {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    fn render_page(&self, tpl: PageTemplate) -> Result<String, anyhow::Error> {
+        render_page(&self.settings().template, tpl)
+    }
+}
+
+/// Part of configuration that is applied again when it is reloaded.
+struct Settings {
+    /// Page template
+    template: String,
+    /// Start page body
+    welcome: String,
+    /// `host:port` pairs telnet sessions are allowed to
+    telnet_allow: HashSet<String>,
+    trusted_proxies: Vec<Cidr>,
+    /// Clients allowed to read metrics, everyone if empty
+    metrics_allow: Vec<Cidr>,
+    /// Requests of each client
+    client_limit: Arc<RateLimit<IpAddr>>,
+    /// Gopher server that has to be reachable for proxy to be ready
+    ready_canary: Option<String>,
+    /// Time requests in flight are given to finish on shutdown
+    shutdown_grace: Duration,
+}
+
+impl Settings {
+    /// Rate limits configured the same as in `previous` settings keep their state.
+    fn new(args: &Args, links: &[Link], previous: Option<(&Args, &Settings)>) -> Result<Self> {
+        let template = match &args.template {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("reading template {}: {}", path, e))?,
+            None => String::from(_PAGE_HTML),
+        };
+        // catch broken template now rather than on every page
+        let page = render_page(
+            &template,
+            PageTemplate {
+                title: String::from("proxy70"),
+                body: String::from(BODY_PLACEHOLDER),
+                url: None,
+                banner: None,
+            },
+        )?;
+        if !page.contains(BODY_PLACEHOLDER) {
+            return Err(anyhow!("page template does not show page body"));
+        }
+        let client_limit = match previous {
+            Some((old, settings))
+                if old.rate_limit == args.rate_limit && old.rate_burst == args.rate_burst =>
+            {
+                settings.client_limit.clone()
+            }
+            _ => Arc::new(RateLimit::new(args.rate_limit, args.rate_burst)),
+        };
+        Ok(Self {
+            template,
+            welcome: _WELCOME_HTML.replace(LINKS_PLACEHOLDER, &links_list(links)),
+            telnet_allow: args.telnet_allow.iter().map(|a| a.to_lowercase()).collect(),
+            trusted_proxies: args.trusted_proxies.clone(),
+            metrics_allow: args.metrics_allow.clone(),
+            client_limit,
+            ready_canary: args.ready_canary.clone(),
+            shutdown_grace: Duration::from_secs(args.shutdown_grace),
+        })
+    }
+}
+
+fn links_list(links: &[Link]) -> String {
+    let mut list = String::new();
+    for link in links {
+        list.push_str(&format!(
+            "* <a href=\"/?url={}\">{}</a>",
+            urlencoding::encode(&link.url),
+            html_escape::encode_text(&link.title)
+        ));
+        if !link.description.is_empty() {
+            list.push_str(&format!(
+                ", {}",
+                html_escape::encode_text(&link.description)
+            ));
+        }
+        list.push('\n');
+    }
+    list
+}
+
+fn default_links() -> Vec<Link> {
+    Config::parse(DEFAULT_LINKS)
+        .ok()
+        .and_then(|c| c.links)
+        .unwrap_or_default()
+}
 
 #[derive(Deserialize)]
 struct ProxyReq {
     url: Option<String>,
     query: Option<String>,
+    refresh: Option<u8>,
+    ask: Option<u8>,
+    info: Option<u8>,
+    decode: Option<u8>,
+    mirrors: Option<Vec<String>>,
 }
 
-/// Crude rate limiter
-#[derive(Clone)]
-struct RateLimiter {
-    peers: Arc<DashMap<String, usize>>,
-    window: Duration,
-    rps: i32,
+#[derive(Deserialize)]
+struct TelnetReq {
+    host: String,
+    port: u16,
 }
 
-impl RateLimiter {
-    fn start(&self) {
-        let peers = self.peers.clone();
-        let window = self.window;
-        task::spawn(async move {
-            loop {
-                peers.iter_mut().for_each(|mut p| *p = 0);
-                task::sleep(window).await;
-            }
-        });
-    }
+/// Address of the client request came from, as seen behind trusted proxies.
+#[derive(Clone, Copy)]
+struct Client(IpAddr);
+
+/// Keeps track of requests until their responses are sent, closing connections on shutdown.
+struct InFlight;
+
+/// Works out client address of requests, logging them along with it.
+struct AccessLog;
+
+/// Counts requests by route and status.
+struct RequestMetrics;
+
+/// Rejects requests of clients exceeding their rate limit before handling them.
+struct RateLimiter;
+
+/// Forgets idle clients and servers now and then, so that limits won't grow forever.
+fn evict_rate_limits(state: State) {
+    task::spawn(async move {
+        loop {
+            task::sleep(RATE_LIMIT_EVICT_INTERVAL).await;
+            state.settings().client_limit.evict();
+            state.cache.options().upstream_limit.evict();
+        }
+    });
 }
 
 #[doc(hidden)]
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value_t = String::from("localhost:8080"))]
-    listen_addr: String,
+    /// Configuration file setting any of the flags by their long names; flags given on
+    /// command line take precedence. Reloaded on SIGHUP
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Address to listen on, HOST:PORT or unix:PATH; may be repeated
+    /// [default: localhost:8080, unless sockets are passed by systemd]
+    #[arg(short, long, visible_alias = "listen-addr", value_name = "ADDR")]
+    listen: Vec<Listen>,
+
+    /// How long responses are kept in cache, seconds
+    #[arg(long, default_value_t = 300)]
+    cache_ttl: u64,
+
+    /// Memory budget for response cache, bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    cache_size: usize,
+
+    /// Directory to persist fetched responses in
+    #[arg(long)]
+    cache_dir: Option<String>,
+
+    /// Disk budget for cache directory, bytes; least recently used responses are removed
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    cache_dir_size: u64,
+
+    /// Serve only responses that are already in cache directory
+    #[arg(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_value_t = false,
+        default_missing_value = "true",
+        requires_if("true", "cache_dir")
+    )]
+    offline: bool,
+
+    /// File to keep certificates of TLS gopher servers pinned on first use in
+    #[arg(long)]
+    known_hosts: Option<String>,
+
+    /// Try TLS for plain gopher:// URLs first, falling back to plain TCP
+    #[arg(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_value_t = false,
+        default_missing_value = "true"
+    )]
+    opportunistic_tls: bool,
+
+    /// Allow in-browser telnet sessions to HOST:PORT, may be repeated
+    #[arg(long, value_name = "HOST:PORT")]
+    telnet_allow: Vec<String>,
+
+    /// Directory to spool downloads in [default: proxy70-spool in system temp directory]
+    #[arg(long)]
+    spool_dir: Option<String>,
+
+    /// Time to connect to gopher server, seconds
+    #[arg(long, default_value_t = 10)]
+    connect_timeout: u64,
+
+    /// Time gopher server may stay silent while sending response, seconds
+    #[arg(long, default_value_t = 30)]
+    read_timeout: u64,
+
+    /// Time whole response has to be received from gopher server in, seconds
+    #[arg(long, default_value_t = 600)]
+    fetch_deadline: u64,
+
+    /// Biggest response accepted from gopher server, bytes
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    max_response_size: u64,
+
+    /// Disk budget for spooled downloads, including ones in progress, bytes;
+    /// downloads that don't fit are passed through without being kept, 0 disables spooling
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    spool_size: u64,
+
+    /// Connect to upstream servers in this network, even if it is blocked by default; may be repeated
+    #[arg(long, value_name = "CIDR")]
+    allow_cidr: Vec<Cidr>,
+
+    /// Never connect to upstream servers in this network, may be repeated
+    #[arg(long, value_name = "CIDR")]
+    deny_cidr: Vec<Cidr>,
+
+    /// Connect to upstream servers only on this port, may be repeated
+    #[arg(long, value_name = "PORT")]
+    allow_port: Vec<u16>,
+
+    /// Never connect to upstream servers on this port, may be repeated
+    #[arg(long, value_name = "PORT")]
+    deny_port: Vec<u16>,
+
+    /// Requests per second each client may make on average, 0 disables limit
+    #[arg(long, default_value_t = 1.0)]
+    rate_limit: f64,
+
+    /// Requests each client may make at once before rate limit kicks in
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_burst: u32,
+
+    /// Requests per second proxied to each upstream host on average, 0 disables limit
+    #[arg(long, default_value_t = 2.0)]
+    upstream_rate_limit: f64,
+
+    /// Requests proxied to each upstream host at once before its rate limit kicks in
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    upstream_rate_burst: u32,
+
+    /// Reverse proxies to take client address from Forwarded or X-Forwarded-For header of,
+    /// and host from X-Forwarded-Host; comma separated, may be repeated
+    #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+    trusted_proxies: Vec<Cidr>,
+
+    /// Serve /metrics only to clients in this network, may be repeated [default: everyone]
+    #[arg(long, value_name = "CIDR")]
+    metrics_allow: Vec<Cidr>,
+
+    /// Gopher server checked by /readyz, proxy is not ready while it can't be connected to
+    #[arg(long, value_name = "HOST:PORT")]
+    ready_canary: Option<String>,
+
+    /// Time requests in flight are given to finish on SIGTERM before exiting, seconds
+    #[arg(long, default_value_t = 30)]
+    shutdown_grace: u64,
+
+    /// Serve HTTPS on TCP addresses with certificate chain from this PEM file,
+    /// reloaded when it changes
+    #[arg(long, requires = "tls_key", value_name = "FILE")]
+    tls_cert: Option<String>,
+
+    /// Private key of HTTPS certificate, PEM
+    #[arg(long, requires = "tls_cert", value_name = "FILE")]
+    tls_key: Option<String>,
+
+    /// Redirect plain HTTP requests coming to this address to HTTPS
+    #[arg(long, requires = "tls_cert", value_name = "HOST:PORT")]
+    http_redirect: Option<String>,
+
+    /// Page template [default: built-in]
+    #[arg(long)]
+    template: Option<String>,
+
+    /// Directory to serve styles, scripts and robots.txt from
+    #[arg(long, default_value_t = String::from("static"))]
+    static_dir: String,
+}
+
+/// Flags that take effect only when proxy is restarted.
+fn restart_required(old: &Args, new: &Args) -> Vec<&'static str> {
+    let changed = [
+        ("listen", old.listen != new.listen),
+        ("cache-ttl", old.cache_ttl != new.cache_ttl),
+        ("cache-size", old.cache_size != new.cache_size),
+        ("cache-dir", old.cache_dir != new.cache_dir),
+        ("cache-dir-size", old.cache_dir_size != new.cache_dir_size),
+        ("known-hosts", old.known_hosts != new.known_hosts),
+        (
+            "opportunistic-tls",
+            old.opportunistic_tls != new.opportunistic_tls,
+        ),
+        ("spool-dir", old.spool_dir != new.spool_dir),
+        ("spool-size", old.spool_size != new.spool_size),
+        ("static-dir", old.static_dir != new.static_dir),
+        ("tls-cert", old.tls_cert != new.tls_cert),
+        ("tls-key", old.tls_key != new.tls_key),
+        ("http-redirect", old.http_redirect != new.http_redirect),
+    ];
+    changed
+        .into_iter()
+        .filter_map(|(flag, changed)| changed.then_some(flag))
+        .collect()
+}
+
+/// Parses command line on top of configuration file it names, if any.
+/// Returns arguments along with start page links.
+fn load_args() -> Result<(Args, Vec<Link>)> {
+    let cli = Args::command().try_get_matches()?;
+    let Some(path) = cli.get_one::<String>("config") else {
+        return Ok((Args::from_arg_matches(&cli)?, default_links()));
+    };
+    let config = Config::read(path)?;
+    let command = Args::command();
+    let argument = |flag: &str| {
+        command.get_arguments().find(|a| {
+            a.get_long() == Some(flag)
+                || a.get_all_aliases()
+                    .is_some_and(|aliases| aliases.contains(&flag))
+        })
+    };
+    config
+        .check(|flag| argument(flag).is_some())
+        .with_context(|| format!("parsing {}", path))?;
+    // flags given on command line replace ones from file rather than add to them
+    let on_command_line = |flag: &str| {
+        argument(flag).is_some_and(|a| {
+            cli.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine)
+        })
+    };
+    let mut argv: Vec<OsString> = std::env::args_os().take(1).collect();
+    argv.extend(config.args(on_command_line).into_iter().map(OsString::from));
+    argv.extend(std::env::args_os().skip(1));
+    let args = Args::try_parse_from(argv)?;
+    Ok((args, config.links.unwrap_or_else(default_links)))
+}
+
+/// Upstream rate limit configured the same as in `previous` options keeps its state.
+fn fetch_options(args: &Args, previous: Option<(&Args, FetchOptions)>) -> FetchOptions {
+    let upstream_limit = match previous {
+        Some((old, options))
+            if old.upstream_rate_limit == args.upstream_rate_limit
+                && old.upstream_rate_burst == args.upstream_rate_burst =>
+        {
+            options.upstream_limit
+        }
+        _ => Arc::new(RateLimit::new(
+            args.upstream_rate_limit,
+            args.upstream_rate_burst,
+        )),
+    };
+    FetchOptions {
+        connect_timeout: Duration::from_secs(args.connect_timeout),
+        read_timeout: Duration::from_secs(args.read_timeout),
+        deadline: Duration::from_secs(args.fetch_deadline),
+        max_bytes: args.max_response_size,
+        policy: Arc::new(Policy {
+            allow: args.allow_cidr.clone(),
+            deny: args.deny_cidr.clone(),
+            allow_ports: args.allow_port.clone(),
+            deny_ports: args.deny_port.clone(),
+        }),
+        upstream_limit,
+    }
+}
+
+/// Reloads configuration on SIGHUP. Requests being served keep settings they started with,
+/// and configuration is left as it was if new one fails to load.
+fn reload_on_hangup(state: State, mut args: Args) -> std::io::Result<()> {
+    let mut signals = Signals::new([Signal::Hup])?;
+    task::spawn(async move {
+        while signals.next().await.is_some() {
+            if let Some(certificates) = &state.certificates {
+                reload_certificates(certificates);
+            }
+            match reload(&state, &args) {
+                Ok(new) => {
+                    log::info!("configuration reloaded");
+                    args = new;
+                }
+                Err(e) => log::error!("failed to reload configuration: {:#}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Checks HTTPS key pair files every now and then, picking up renewed certificates.
+fn watch_certificates(certificates: Arc<Certificates>) {
+    task::spawn(async move {
+        loop {
+            task::sleep(CERTIFICATES_CHECK_INTERVAL).await;
+            reload_certificates(&certificates);
+        }
+    });
+}
+
+fn reload_certificates(certificates: &Certificates) {
+    match certificates.reload_if_changed() {
+        Ok(true) => log::info!("HTTPS certificate reloaded"),
+        Ok(false) => {}
+        Err(e) => log::error!("failed to reload HTTPS certificate: {:#}", e),
+    }
+}
+
+fn reload(state: &State, old: &Args) -> Result<Args> {
+    let (args, links) = load_args()?;
+    let settings = Settings::new(&args, &links, Some((old, &state.settings())))?;
+    for flag in restart_required(old, &args) {
+        log::warn!("change of {} takes effect after restart", flag);
+    }
+    let options = fetch_options(&args, Some((old, state.cache.options())));
+    state.cache.set_options(options);
+    state.cache.set_offline(args.offline);
+    *state.settings.write().unwrap() = Arc::new(settings);
+    Ok(args)
 }
 
 #[derive(Serialize)]
@@ -57,160 +522,1092 @@ struct PageTemplate {
     title: String,
     body: String,
     url: Option<String>,
+    banner: Option<String>,
 }
 
 #[tide::utils::async_trait]
-impl Middleware<()> for RateLimiter {
-    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
-        let mut reqs = 0;
-        if let Some(Ok(peer)) = req.peer_addr().map(str::parse::<std::net::SocketAddr>) {
-            let peer = peer.ip().to_string();
-            if let Some(mut x) = self.peers.get_mut(&peer) {
-                *x += 1;
-                reqs = *x;
-            } else {
-                self.peers.insert(peer, 1);
-                reqs = 1;
-            }
+impl Middleware<State> for InFlight {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let shutdown = req.state().shutdown.clone();
+        let guard = shutdown.start();
+        let mut res = next.run(req).await;
+        // request is done once its body is sent, which for downloads may take a while
+        let body = res.take_body();
+        let (len, mime) = (body.len(), body.mime().clone());
+        let mut body = Body::from_reader(GuardedBody::new(body, guard), len);
+        body.set_mime(mime);
+        res.set_body(body);
+        if shutdown.is_draining() {
+            res.insert_header("Connection", "close");
         }
+        Ok(res)
+    }
+}
+
+/// Address request came from, which may be a reverse proxy.
+fn peer_ip(req: &Request<State>) -> IpAddr {
+    // peers of Unix sockets have no address, but they are local processes all the same
+    match req.peer_addr().map(str::parse::<SocketAddr>) {
+        Some(Ok(peer)) => peer.ip(),
+        _ => IpAddr::from(Ipv4Addr::LOCALHOST),
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for AccessLog {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let header = |name| {
+            req.header(name)
+                .map(|h| h.iter().map(|v| v.as_str()).collect::<Vec<_>>().join(","))
+        };
+        let client = forwarded::client_ip(
+            peer_ip(&req),
+            header("Forwarded").as_deref(),
+            header("X-Forwarded-For").as_deref(),
+            &req.state().settings().trusted_proxies,
+        );
+        req.set_ext(Client(client));
+        let (method, path) = (req.method(), String::from(req.url().path()));
+        let started = Instant::now();
         let res = next.run(req).await;
-        if reqs as f32 > self.rps as f32 * self.window.as_secs_f32() {
-            return Err(tide::Error::new(
-                StatusCode::TooManyRequests,
-                anyhow!("rate limited"),
-            ));
+        let status = res.status();
+        match res.error() {
+            Some(e) if status.is_server_error() => {
+                log::error!("{} {} {} {}: {}", client, method, path, status, e)
+            }
+            _ => log::info!(
+                "{} {} {} {} {:?}",
+                client,
+                method,
+                path,
+                status,
+                started.elapsed()
+            ),
         }
         Ok(res)
     }
 }
 
-fn render_page(tpl: PageTemplate) -> Result<String, anyhow::Error> {
+#[tide::utils::async_trait]
+impl Middleware<State> for RequestMetrics {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // routes rather than paths, so that every static file won't get its own counter
+        let route = match req.url().path() {
+            path @ ("/" | "/telnet" | "/robots.txt" | "/metrics" | "/healthz" | "/readyz") => {
+                String::from(path)
+            }
+            path if path.starts_with("/static/") => String::from("/static"),
+            _ => String::from("other"),
+        };
+        let res = next.run(req).await;
+        METRICS
+            .requests
+            .inc(&[&route, &u16::from(res.status()).to_string()]);
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RateLimiter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // health checks come often and from few addresses, they must not be turned away
+        let probe = matches!(req.url().path(), "/healthz" | "/readyz");
+        if let Some(Client(ip)) = req.ext::<Client>().copied().filter(|_| !probe) {
+            if let Err(wait) = req.state().settings().client_limit.take(ip) {
+                METRICS.rate_limited.inc(&["client"]);
+                return Ok(too_many_requests(wait, "too many requests, slow down"));
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+fn too_many_requests(retry_after: Duration, message: &str) -> tide::Response {
+    tide::Response::builder(StatusCode::TooManyRequests)
+        .header("Retry-After", retry_after.as_secs_f64().ceil().to_string())
+        .body(message)
+        .build()
+}
+
+fn render_page(template: &str, tpl: PageTemplate) -> Result<String, anyhow::Error> {
     let mut tt = TinyTemplate::new();
-    tt.add_template("page", _PAGE_HTML)?;
+    tt.add_template("page", template)?;
     Ok(tt.render("page", &tpl)?)
 }
 
-async fn render_nav(mut _req: Request<()>) -> tide::Result {
+/// Sends page to browser while its body is still being rendered: template head goes
+/// right away, and body chunks follow as `render` produces them.
+/// Headers are already sent by the time body fails, so error is shown in place.
+fn stream_page<F, Fut>(
+    state: &State,
+    url: String,
+    banner: Option<String>,
+    render: F,
+) -> tide::Result
+where
+    F: FnOnce(Sender<String>) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let page = state.render_page(PageTemplate {
+        title: String::from("proxy70"),
+        body: String::from(BODY_PLACEHOLDER),
+        url: Some(url),
+        banner,
+    })?;
+    let Some((head, tail)) = page.split_once(BODY_PLACEHOLDER) else {
+        return Err(anyhow!("page template has no body").into());
+    };
+    let (head, tail) = (String::from(head), String::from(tail));
+    let (tx, reader) = body::channel();
+    let rendering = render(tx.clone());
+    task::spawn(async move {
+        if tx.send(head).await.is_err() {
+            return;
+        }
+        if let Err(err) = rendering.await {
+            let _ = tx.send(body::error(&err)).await;
+        }
+        let _ = tx.send(tail).await;
+    });
+    Ok(tide::Response::builder(200)
+        .body(Body::from_reader(reader, None))
+        .content_type(mime::HTML)
+        .build())
+}
+
+async fn render_metrics(req: Request<State>) -> tide::Result {
+    // metrics name upstream hosts and traffic, which not every operator wants to publish
+    let allow = &req.state().settings().metrics_allow;
+    let allowed = match req.ext::<Client>() {
+        Some(Client(ip)) => allow.is_empty() || allow.iter().any(|c| c.contains(*ip)),
+        None => allow.is_empty(),
+    };
+    if !allowed {
+        return Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            "metrics are not available to this client",
+        ));
+    }
+    Ok(tide::Response::builder(200)
+        .body(METRICS.render())
+        .content_type("text/plain; version=0.0.4")
+        .build())
+}
+
+/// Process is up and serving requests.
+async fn healthz(_req: Request<State>) -> tide::Result {
+    Ok(tide::Response::builder(200).body("ok").build())
+}
+
+/// Proxy accepts requests and is able to reach gopher servers, as far as canary tells.
+async fn readyz(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    if state.shutdown.is_draining() {
+        return Ok(not_ready("shutting down"));
+    }
+    if let Some(canary) = &state.settings().ready_canary {
+        let timeout = state.cache.options().connect_timeout;
+        if let Err(e) = io::timeout(timeout, TcpStream::connect(canary.as_str())).await {
+            log::warn!("canary {} is unreachable: {}", canary, e);
+            return Ok(not_ready(&format!("canary {} is unreachable", canary)));
+        }
+    }
+    Ok(tide::Response::builder(200).body("ok").build())
+}
+
+fn not_ready(reason: &str) -> tide::Response {
+    tide::Response::builder(StatusCode::ServiceUnavailable)
+        .body(reason)
+        .build()
+}
+
+async fn render_nav(req: Request<State>) -> tide::Result {
+    let state = req.state();
     let resp = tide::Response::builder(200)
-        .body(render_page(PageTemplate {
+        .body(state.render_page(PageTemplate {
             title: String::from("proxy70"),
-            body: String::from(_WELCOME_HTML),
+            body: state.settings().welcome.clone(),
             url: None,
+            banner: None,
         })?)
         .content_type(mime::HTML)
         .build();
     Ok(resp)
 }
 
-async fn root(req: Request<()>) -> tide::Result {
+async fn root(req: Request<State>) -> tide::Result {
     let r: ProxyReq = req.query()?;
+    let state = req.state();
+    let refresh = r.refresh.unwrap_or(0) != 0;
     match r.url {
         None => render_nav(req).await,
         Some(url_str) => {
-            let url = GopherURL::try_from(url_str.as_str())?;
-
-            let result = match url.gopher_type {
-                GopherItem::Submenu => render_submenu(&url, None).await,
-                GopherItem::FullTextSearch => render_submenu(&url, r.query).await,
-                GopherItem::TextFile => render_text(&url).await,
-                t => proxy_file(&url, t).await,
+            let (url, result) = if url_str.starts_with("gemini://") {
+                let url = GeminiURL::try_from(url_str.as_str())?;
+                (url.to_string(), render_gemini(state, &url, r.query).await)
+            } else if url_str.starts_with("finger://") {
+                let url = FingerURL::try_from(url_str.as_str())?;
+                (url.to_string(), render_finger(state, &url).await)
+            } else {
+                let mut url = GopherURL::try_from(url_str.as_str())?;
+                for mirror in r.mirrors.iter().flatten() {
+                    url.mirrors.push(GopherURL {
+                        gopher_type: url.gopher_type,
+                        ..GopherURL::try_from(mirror.as_str())?
+                    });
+                }
+                let result = match url.gopher_type {
+                    _ if r.info.unwrap_or(0) != 0 => render_info(state, &url).await,
+                    _ if r.ask.unwrap_or(0) != 0 => render_ask(state, &url).await,
+                    GopherItem::Submenu => render_submenu(state, &url, None, refresh).await,
+                    GopherItem::FullTextSearch => {
+                        render_submenu(state, &url, r.query, refresh).await
+                    }
+                    GopherItem::TextFile => render_text(state, &url, refresh).await,
+                    GopherItem::Nameserver => render_nameserver(state, &url, r.query).await,
+                    GopherItem::Telnet => render_telnet(state, &url).await,
+                    GopherItem::Telnet3270 => Err(anyhow!(
+                        "tn3270 sessions are not supported, use a 3270 emulator to connect to {}:{}",
+                        url.host,
+                        url.port
+                    )
+                    .into()),
+                    t @ (GopherItem::UuencodeFile | GopherItem::BinHex)
+                        if r.decode.unwrap_or(0) != 0 =>
+                    {
+                        proxy_decoded(&state.cache, &url, t).await
+                    }
+                    t => {
+                        proxy_file(
+                            state,
+                            &url,
+                            t,
+                            req.header("Range").map(|h| h.as_str()),
+                            req.header("If-Range").map(|h| h.as_str()),
+                        )
+                        .await
+                    }
+                };
+                (url.to_string(), result)
             };
 
             match result {
                 Ok(resp) => Ok(resp),
-                Err(err) => Ok(tide::Response::builder(200)
-                    .body(render_page(PageTemplate {
-                        title: String::from("proxy70"),
-                        body: format!("<pre>error loading resource: {:} </pre>", err),
-                        url: Some(url.to_string()),
-                    })?)
-                    .content_type(mime::HTML)
-                    .build()),
+                Err(err) => render_error(state, url, err),
             }
         }
     }
 }
 
-async fn proxy_file(url: &GopherURL, t: GopherItem) -> tide::Result {
-    let response = gopher::fetch_url(url, None).await?;
-    let body = Body::from_reader(response, None);
-    let mut builder = tide::Response::builder(200);
-    if let Some(filename) = url.selector.split("/").last() {
-        builder = builder.header(
-            "Content-disposition",
-            format!("attachement; filename=\"{}\"", filename),
-        );
+fn render_error(state: &State, url: String, err: tide::Error) -> tide::Result {
+    if let Some(throttled) = err.downcast_ref::<Throttled>() {
+        return Ok(too_many_requests(
+            throttled.retry_after,
+            &throttled.to_string(),
+        ));
     }
+    let mut status = StatusCode::Ok;
+    let body = if err.downcast_ref::<NotCached>().is_some() {
+        String::from(
+            "<pre>proxy70 is offline and this resource was never visited, so it is not cached.</pre>",
+        )
+    } else if let Some(blocked) = err.downcast_ref::<Blocked>() {
+        status = StatusCode::Forbidden;
+        format!(
+            "<pre>blocked by policy: this proxy70 instance does not connect to {}.</pre>",
+            html_escape::encode_text(&blocked.addr.to_string())
+        )
+    } else {
+        body::error(&err)
+    };
+    Ok(tide::Response::builder(status)
+        .body(state.render_page(PageTemplate {
+            title: String::from("proxy70"),
+            body,
+            url: Some(url),
+            banner: None,
+        })?)
+        .content_type(mime::HTML)
+        .build())
+}
 
-    Ok(builder.body(body).content_type(t).build())
+async fn proxy_file(
+    state: &State,
+    url: &GopherURL,
+    t: GopherItem,
+    range: Option<&str>,
+    if_range: Option<&str>,
+) -> tide::Result {
+    let filename = url.selector.split('/').next_back().unwrap_or_default();
+    if let Some(spool) = &state.spool {
+        return match spool.fetch(&state.cache, url).await? {
+            Spooled::File(file) => proxy_spooled(file, t, filename, range, if_range).await,
+            Spooled::Stream(response) => proxy_stream(response, t, filename).await,
+        };
+    }
+    // without spool ranges would have to be cut out of the whole file kept in memory,
+    // so they are not offered at all
+    let response = state.cache.fetch_stream(url, None, false).await?;
+    proxy_stream(response.data, t, filename).await
 }
 
-async fn render_text(url: &GopherURL) -> tide::Result {
-    let mut body = String::new();
-    body.push_str("<pre>\n");
-    let mut lines = gopher::fetch_url(&url, None).await?.lines();
+async fn proxy_stream(
+    mut response: cache::Response,
+    t: GopherItem,
+    filename: &str,
+) -> tide::Result {
+    // first chunk is what fetch_url has peeked into already
+    let head = futures_lite::AsyncBufReadExt::fill_buf(&mut response).await?;
+    let mime = sniff::sniff(head, Some(filename)).unwrap_or(t.into());
+    Ok(content_response(StatusCode::Ok, mime, filename)
+        .body(Body::from_reader(response, None))
+        .build())
+}
 
-    while let Some(Ok(line)) = lines.next().await {
-        if line == "." {
-            break;
+/// Serves file from spool with known length, or its part.
+async fn proxy_spooled(
+    spooled: SpooledFile,
+    t: GopherItem,
+    filename: &str,
+    range: Option<&str>,
+    if_range: Option<&str>,
+) -> tide::Result {
+    let SpooledFile {
+        mut file,
+        len,
+        etag,
+    } = spooled;
+    let mut head = vec![0; SNIFF_LEN.min(len as usize)];
+    file.read_exact(&mut head).await?;
+    let mime = sniff::sniff(&head, Some(filename)).unwrap_or(t.into());
+    let range = range.filter(|_| range::if_range(if_range, Some(&etag)));
+    let (status, start, count, content_range) = select_range(range, len);
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut response = file_response(status, mime, filename).header("ETag", etag);
+    if let Some(content_range) = content_range {
+        response = response.header("Content-Range", content_range);
+    }
+    Ok(response
+        .body(Body::from_reader(
+            BufReader::new(file.take(count)),
+            Some(count as usize),
+        ))
+        .build())
+}
+
+/// Picks part of content of given length to serve: response status, first byte,
+/// number of bytes and `Content-Range` header, if any.
+fn select_range(range: Option<&str>, len: u64) -> (StatusCode, u64, u64, Option<String>) {
+    match range.and_then(|r| range::parse(r, len)) {
+        Some(Ok((start, end))) => (
+            StatusCode::PartialContent,
+            start,
+            end - start + 1,
+            Some(format!("bytes {}-{}/{}", start, end, len)),
+        ),
+        Some(Err(range::Unsatisfiable)) => (
+            StatusCode::RequestedRangeNotSatisfiable,
+            0,
+            0,
+            Some(format!("bytes */{}", len)),
+        ),
+        // ranges we don't understand are ignored
+        None => (StatusCode::Ok, 0, len, None),
+    }
+}
+
+fn file_response(status: StatusCode, mime: Mime, filename: &str) -> tide::ResponseBuilder {
+    content_response(status, mime, filename).header("Accept-Ranges", "bytes")
+}
+
+/// Response with content of upstream file, shown inline only if it is safe to.
+/// Browser is told not to second-guess its type, and to keep it away from proxy's origin
+/// even if shown.
+fn content_response(status: StatusCode, mime: Mime, filename: &str) -> tide::ResponseBuilder {
+    tide::Response::builder(status)
+        .header("Content-disposition", content_disposition(&mime, filename))
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Content-Security-Policy", "sandbox")
+        .content_type(mime)
+}
+
+/// Lets browser show media it can handle instead of downloading it.
+fn content_disposition(mime: &Mime, filename: &str) -> String {
+    let filename = filename.replace(|c: char| c == '"' || c.is_control(), "_");
+    let disposition = if sniff::is_inline(mime) {
+        "inline"
+    } else {
+        "attachment"
+    };
+    if filename.is_empty() {
+        String::from(disposition)
+    } else {
+        format!("{}; filename=\"{}\"", disposition, filename)
+    }
+}
+
+/// Serves uuencoded or BinHex file decoded, with its original name.
+async fn proxy_decoded(cache: &Arc<Cache>, url: &GopherURL, t: GopherItem) -> tide::Result {
+    let response = cache.fetch_stream(url, None, false).await?.data;
+    let decoder: Box<dyn decode::Decoder + Send + Sync> = match t {
+        GopherItem::BinHex => Box::new(decode::BinHexDecoder::default()),
+        _ => Box::new(decode::UuDecoder::default()),
+    };
+    let mut reader = decode::DecodingReader::new(response, decoder);
+    reader.prefetch(SNIFF_LEN).await?;
+    let Some(filename) = reader.filename().map(String::from) else {
+        return Err(anyhow!("no encoded file found").into());
+    };
+    let mime = sniff::sniff(reader.buffered(), Some(&filename)).unwrap_or(mime::BYTE_STREAM);
+    Ok(content_response(StatusCode::Ok, mime, &filename)
+        .body(Body::from_reader(BufReader::new(reader), None))
+        .build())
+}
+
+async fn render_gemini(state: &State, url: &GeminiURL, input: Option<String>) -> tide::Result {
+    let url = match input {
+        Some(input) => url.with_input(&input),
+        None => url.clone(),
+    };
+    // gemini responses are not cached, so there is nothing to show offline,
+    // same goes for finger and CSO
+    if state.cache.is_offline() {
+        return Err(NotCached(url.to_string()).into());
+    }
+    let (response, url) = gemini::fetch_url(&url, &state.tls, &state.cache.options()).await?;
+    let body = match response {
+        gemini::Response::Input { prompt, sensitive } => format!(
+            r#"<form action="/" method="get">
+                <pre>{}</pre>
+                <input name="query" type="{}">
+                <input type="hidden" name="url" value="{}">
+                <input type="submit" value="Submit">
+            </form>"#,
+            html_escape::encode_text(&prompt),
+            if sensitive { "password" } else { "text" },
+            html_escape::encode_double_quoted_attribute(&url.to_string()),
+        ),
+        gemini::Response::Success { mime, body } if mime.starts_with("text/gemini") => {
+            gemini::render(&String::from_utf8_lossy(&body), &url)
+        }
+        gemini::Response::Success { mime, body } if mime.starts_with("text/") => format!(
+            "<pre>{}</pre>",
+            html_escape::encode_text(&String::from_utf8_lossy(&body))
+        ),
+        gemini::Response::Success { mime, body } => {
+            let path = url.path.split('?').next().unwrap_or_default();
+            let filename = path.rsplit('/').next().unwrap_or_default();
+            let mime = sniff::sniff(&body[..body.len().min(SNIFF_LEN)], Some(filename))
+                .or_else(|| Mime::from_str(&mime).ok())
+                .unwrap_or(mime::BYTE_STREAM);
+            return Ok(content_response(StatusCode::Ok, mime, filename)
+                .body(body)
+                .build());
+        }
+        gemini::Response::Failure { status, message } => {
+            return Err(anyhow!("gemini server responded with {}: {}", status, message).into());
         }
-        body.push_str(&html_escape::encode_text(&line));
-        body.push_str("\n");
+    };
+    Ok(tide::Response::builder(200)
+        .body(state.render_page(PageTemplate {
+            title: String::from("proxy70"),
+            body,
+            url: Some(url.to_string()),
+            banner: None,
+        })?)
+        .content_type(mime::HTML)
+        .build())
+}
+
+async fn render_finger(state: &State, url: &FingerURL) -> tide::Result {
+    if state.cache.is_offline() {
+        return Err(NotCached(url.to_string()).into());
     }
-    body.push_str("</pre>");
+    let response = finger::fetch_url(url, &state.cache.options()).await?;
     Ok(tide::Response::builder(200)
-        .body(render_page(PageTemplate {
+        .body(state.render_page(PageTemplate {
             title: String::from("proxy70"),
-            body: body,
+            body: format!("<pre>\n{}</pre>", gopher::decode_ansi_style(&response)),
             url: Some(url.to_string()),
+            banner: None,
         })?)
         .content_type(mime::HTML)
         .build())
 }
 
-async fn render_submenu(url: &GopherURL, query: Option<String>) -> tide::Result {
+/// Shows CSO/ph query form along with results of the query, if any.
+async fn render_nameserver(state: &State, url: &GopherURL, query: Option<String>) -> tide::Result {
+    if state.cache.is_offline() {
+        return Err(NotCached(url.to_string()).into());
+    }
+    let options = state.cache.options();
+    let mut body = format!(
+        r#"<form action="/" method="get">
+            <input name="query" type="text" placeholder="name=smith" value="{}">
+            <input type="hidden" name="url" value="{}">
+            <input type="submit" value="Look up">
+        </form>
+        "#,
+        html_escape::encode_double_quoted_attribute(query.as_deref().unwrap_or_default()),
+        html_escape::encode_double_quoted_attribute(&url.to_string()),
+    );
+    match query.as_deref().map(str::trim) {
+        Some(query) if !query.is_empty() => {
+            let entries = cso::query(&url.host, url.port, query, &options).await?;
+            body.push_str(&cso_table(&entries));
+        }
+        _ => {
+            let fields = cso::fields(&url.host, url.port, &options).await?;
+            body.push_str("<table>\n");
+            for field in fields.iter().filter(|f| f.lookup) {
+                body.push_str(&format!(
+                    "<tr><td><pre>{}</pre></td><td>{}</td></tr>\n",
+                    html_escape::encode_text(&field.name),
+                    html_escape::encode_text(&field.description),
+                ));
+            }
+            body.push_str("</table>\n");
+        }
+    }
+    Ok(tide::Response::builder(200)
+        .body(state.render_page(PageTemplate {
+            title: String::from("proxy70"),
+            body,
+            url: Some(url.to_string()),
+            banner: None,
+        })?)
+        .content_type(mime::HTML)
+        .build())
+}
+
+/// Renders ph entries as table with a column per field.
+fn cso_table(entries: &[cso::Entry]) -> String {
+    if entries.is_empty() {
+        return String::from("<pre>no matches</pre>");
+    }
+    let mut columns: Vec<&str> = Vec::new();
+    for (name, _) in entries.iter().flat_map(|e| e.fields.iter()) {
+        if !columns.contains(&name.as_str()) {
+            columns.push(name);
+        }
+    }
+    let mut table = String::from("<table class=\"cso\">\n<tr>");
+    for column in &columns {
+        table.push_str(&format!("<th>{}</th>", html_escape::encode_text(column)));
+    }
+    table.push_str("</tr>\n");
+    for entry in entries {
+        table.push_str("<tr>");
+        for column in &columns {
+            let value = entry
+                .fields
+                .iter()
+                .find(|(name, _)| name == column)
+                .map_or("", |(_, value)| value.as_str());
+            table.push_str(&format!(
+                "<td>{}</td>",
+                html_escape::encode_text(value).replace('\n', "<br>")
+            ));
+        }
+        table.push_str("</tr>\n");
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+fn telnet_allowed(state: &State, host: &str, port: u16) -> bool {
+    state
+        .settings()
+        .telnet_allow
+        .contains(&format!("{}:{}", host.to_lowercase(), port))
+}
+
+async fn render_telnet(state: &State, url: &GopherURL) -> tide::Result {
+    if !telnet_allowed(state, &url.host, url.port) {
+        return Err(anyhow!(
+            "telnet sessions to {}:{} are not allowed",
+            url.host,
+            url.port
+        )
+        .into());
+    }
     let mut body = String::new();
-    let menu = gopher::Menu::from_url(&url, query).await?;
-    body.push_str("<table>\n");
-    for item in menu.items {
-        match item.format_row() {
-            Some(content) => body.push_str(format!("<tr>{}</tr>", content).as_str()),
-            None => {
-                continue;
+    if !url.selector.is_empty() {
+        body.push_str(&format!(
+            "<pre>log in as: {}</pre>\n",
+            html_escape::encode_text(&url.selector)
+        ));
+    }
+    body.push_str(&format!(
+        r#"<pre id="terminal" class="terminal" tabindex="0"></pre>
+        <script>
+            const ANSI_COLORS = {};
+            const TELNET_URL = "/telnet?host={}&port={}";
+        </script>
+        <script src="/static/terminal.js"></script>"#,
+        json!(gopher::_ANSI_COLORS),
+        urlencoding::encode(&url.host),
+        url.port,
+    ));
+    Ok(tide::Response::builder(200)
+        .body(state.render_page(PageTemplate {
+            title: String::from("proxy70"),
+            body,
+            url: Some(url.to_string()),
+            banner: None,
+        })?)
+        .content_type(mime::HTML)
+        .build())
+}
+
+/// Upgrades request to WebSocket and bridges it to telnet server.
+async fn telnet_socket(req: Request<State>) -> tide::Result {
+    let r: TelnetReq = req.query()?;
+    // session would keep shutdown waiting for the whole grace period
+    if req.state().shutdown.is_draining() {
+        return Ok(not_ready("shutting down"));
+    }
+    if !telnet_allowed(req.state(), &r.host, r.port) {
+        return Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            "telnet session is not allowed",
+        ));
+    }
+    let is_websocket = req
+        .header("Upgrade")
+        .is_some_and(|h| h.as_str().eq_ignore_ascii_case("websocket"));
+    let Some(key) = req.header("Sec-WebSocket-Key").filter(|_| is_websocket) else {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "websocket upgrade expected",
+        ));
+    };
+    // browsers let any page open websockets, Origin tells where the page came from
+    let origin = req.header("Origin").map(|h| h.as_str());
+    let host = forwarded::host(
+        peer_ip(&req),
+        req.header("Host").map(|h| h.as_str()).unwrap_or_default(),
+        req.header("X-Forwarded-Host").map(|h| h.last().as_str()),
+        &req.state().settings().trusted_proxies,
+    );
+    if origin.is_some_and(|o| !websocket::same_origin(o, host)) {
+        return Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            "websocket origin does not match host",
+        ));
+    }
+    let addr = format!("{}:{}", r.host, r.port);
+    let policy = req.state().cache.options().policy;
+    let connect =
+        async_std::future::timeout(TELNET_CONNECT_TIMEOUT, policy.connect(&r.host, r.port));
+    let server = match connect.await {
+        Ok(Ok(server)) => server,
+        Ok(Err(e)) if e.is::<Blocked>() => {
+            return Err(tide::Error::new(StatusCode::Forbidden, e));
+        }
+        Ok(Err(e)) => return Err(e.into()),
+        Err(_) => return Err(anyhow!("telnet server did not accept connection").into()),
+    };
+
+    let mut resp = tide::Response::new(StatusCode::SwitchingProtocols);
+    resp.insert_header("Upgrade", "websocket");
+    resp.insert_header("Connection", "Upgrade");
+    resp.insert_header("Sec-WebSocket-Accept", websocket::accept_key(key.as_str()));
+    let upgrade = AsMut::<tide::http::Response>::as_mut(&mut resp)
+        .recv_upgrade()
+        .await;
+    // session outlives request, shutdown waits for it separately
+    let guard = req.state().shutdown.start();
+    task::spawn(async move {
+        let _guard = guard;
+        if let Some(socket) = upgrade.await {
+            log::info!("telnet session to {} started", addr);
+            if let Err(e) = telnet::relay(socket, server).await {
+                log::warn!("telnet session to {} failed: {}", addr, e);
             }
+            log::info!("telnet session to {} finished", addr);
+        }
+    });
+    Ok(resp)
+}
+
+/// Tells user that page is outdated and is being refreshed,
+/// or that it was served by a mirror.
+fn banner<T>(response: &Fetched<T>) -> Option<String> {
+    let mut banner = Vec::new();
+    if let Some(mirror) = &response.mirror {
+        banner.push(format!(
+            "primary server is unreachable, served by mirror {}:{}",
+            mirror.host, mirror.port
+        ));
+    }
+    if response.refreshing {
+        let age = response.age.as_secs();
+        let age = match age {
+            0..=119 => format!("{} seconds", age),
+            120..=7199 => format!("{} minutes", age / 60),
+            7200..=172799 => format!("{} hours", age / 3600),
+            _ => format!("{} days", age / 86400),
         };
+        banner.push(format!("cached {} ago, refreshing", age));
     }
-    body.push_str("</table>\n");
+    (!banner.is_empty()).then(|| banner.join("; "))
+}
+
+async fn render_text(state: &State, url: &GopherURL, refresh: bool) -> tide::Result {
+    let response = state.cache.fetch_stream(url, None, refresh).await?;
+    stream_page(state, url.to_string(), banner(&response), |tx| {
+        text_body(response.data, tx)
+    })
+}
+
+async fn text_body(mut response: impl BufRead + Unpin, tx: Sender<String>) -> Result<()> {
+    tx.send(String::from("<pre>\n")).await?;
+    let mut buf = Vec::new();
+
+    while let Some(line) = gopher::read_line(&mut response, &mut buf).await? {
+        if line == "." {
+            break;
+        }
+        tx.send(format!("{}\n", html_escape::encode_text(&line)))
+            .await?;
+    }
+    // response is cached once it is read till the end, which comes after the final dot
+    io::copy(&mut response, &mut io::sink()).await?;
+    tx.send(String::from("</pre>")).await?;
+    Ok(())
+}
+
+async fn render_submenu(
+    state: &State,
+    url: &GopherURL,
+    query: Option<String>,
+    refresh: bool,
+) -> tide::Result {
+    let response = state.cache.fetch_stream(url, query, refresh).await?;
+    let base = url.clone();
+    stream_page(state, url.to_string(), banner(&response), |tx| {
+        menu_body(base, response.data, tx)
+    })
+}
+
+/// Renders menu rows as soon as menu lines arrive.
+async fn menu_body(
+    url: GopherURL,
+    mut response: impl BufRead + Unpin,
+    tx: Sender<String>,
+) -> Result<()> {
+    tx.send(String::from("<table>\n")).await?;
+    let mut parser = gopher::MenuParser::default();
+    let mut buf = Vec::new();
+    while let Some(line) = gopher::read_line(&mut response, &mut buf).await? {
+        if line == "." {
+            break;
+        }
+        if let Some(item) = parser.push(&line) {
+            send_row(&tx, &url, item).await?;
+        }
+    }
+    io::copy(&mut response, &mut io::sink()).await?;
+    if let Some(item) = parser.finish() {
+        send_row(&tx, &url, item).await?;
+    }
+    tx.send(String::from("</table>\n")).await?;
+    Ok(())
+}
+
+async fn send_row(tx: &Sender<String>, base: &GopherURL, mut item: gopher::DirEntry) -> Result<()> {
+    item.inherit_tls(base);
+    if let Some(content) = item.format_row() {
+        tx.send(format!("<tr>{}</tr>", content)).await?;
+    }
+    Ok(())
+}
+
+/// Fetches attribute block of Gopher+ item.
+async fn attributes(cache: &Arc<Cache>, url: &GopherURL) -> Result<gopher::Attributes> {
+    let response = cache.fetch(&url.attributes(), None, false).await?;
+    Ok(gopher::Attributes::parse(&String::from_utf8_lossy(
+        &response.data,
+    )))
+}
+
+/// Shows abstract, admin info and alternate views of Gopher+ item.
+async fn render_info(state: &State, url: &GopherURL) -> tide::Result {
+    let attrs = attributes(&state.cache, url).await?;
+    let body = match attrs.to_html(url) {
+        html if html.is_empty() => String::from("<p>item has no attributes to show</p>"),
+        html => html,
+    };
+    Ok(tide::Response::builder(200)
+        .body(state.render_page(PageTemplate {
+            title: String::from("proxy70"),
+            body,
+            url: Some(url.to_string()),
+            banner: None,
+        })?)
+        .content_type(mime::HTML)
+        .build())
+}
+
+/// Fetches ASK block of Gopher+ item.
+async fn ask_fields(cache: &Arc<Cache>, url: &GopherURL) -> Result<Vec<gopher::AskField>> {
+    let attrs = attributes(cache, url).await?;
+    if attrs.ask.is_empty() {
+        return Err(anyhow!("item has no questions to ask"));
+    }
+    Ok(attrs.ask)
+}
+
+async fn render_ask(state: &State, url: &GopherURL) -> tide::Result {
+    let fields = ask_fields(&state.cache, url).await?;
     Ok(tide::Response::builder(200)
-        .body(render_page(PageTemplate {
+        .body(state.render_page(PageTemplate {
             title: String::from("proxy70"),
-            body: body,
+            body: gopher::ask_form(url, &fields),
             url: Some(url.to_string()),
+            banner: None,
         })?)
         .content_type(mime::HTML)
         .build())
 }
 
+/// Sends answers to ASK form upstream, responses are never cached.
+async fn submit_ask(mut req: Request<State>) -> tide::Result {
+    let form: HashMap<String, String> = req.body_form().await?;
+    let Some(url_str) = form.get("url") else {
+        return Err(tide::Error::from_str(StatusCode::BadRequest, "no url"));
+    };
+    let url = GopherURL::try_from(url_str.as_str())?;
+    let state = req.state();
+    let result = async {
+        let fields = ask_fields(&state.cache, &url).await?;
+        let response =
+            gopher::submit_ask(&url, &fields, &form, &state.tls, &state.cache.options()).await?;
+        match url.gopher_type {
+            GopherItem::Submenu | GopherItem::FullTextSearch => {
+                let base = url.clone();
+                stream_page(state, url.to_string(), None, |tx| {
+                    menu_body(base, response, tx)
+                })
+            }
+            GopherItem::TextFile => {
+                stream_page(state, url.to_string(), None, |tx| text_body(response, tx))
+            }
+            t => {
+                let filename = url.selector.split('/').next_back().unwrap_or_default();
+                Ok(content_response(StatusCode::Ok, t.into(), filename)
+                    .body(Body::from_reader(response, None))
+                    .build())
+            }
+        }
+    };
+    match result.await {
+        Ok(resp) => Ok(resp),
+        Err(err) => render_error(state, url.to_string(), err),
+    }
+}
+
+/// Listens on addresses given and sockets passed by systemd, on default address if there
+/// are neither. With `certificates` TCP ones serve HTTPS, port of the first is returned.
+fn listener(
+    addrs: &[Listen],
+    certificates: Option<&Arc<Certificates>>,
+) -> Result<(ConcurrentListener<State>, Option<u16>)> {
+    let acceptor = certificates.map(|c| c.acceptor()).transpose()?;
+    let mut listener = ConcurrentListener::new();
+    // sockets to serve HTTPS on
+    let mut https = Vec::new();
+    let inherited = listen::inherited()?;
+    let default = [Listen::Tcp(String::from(DEFAULT_LISTEN))];
+    let addrs = match addrs.is_empty() && inherited.is_empty() {
+        true => &default[..],
+        false => addrs,
+    };
+    for addr in addrs {
+        match addr {
+            Listen::Tcp(addr) if acceptor.is_some() => https.push(
+                std::net::TcpListener::bind(addr.as_str())
+                    .with_context(|| format!("listening on {}", addr))?,
+            ),
+            Listen::Tcp(addr) => listener.add(addr.as_str())?,
+            Listen::Unix(path) => {
+                listen::remove_stale_socket(path)?;
+                listener.add(path.clone())?
+            }
+        }
+    }
+    for socket in inherited {
+        match socket {
+            Inherited::Tcp(socket) if acceptor.is_some() => https.push(socket),
+            Inherited::Tcp(socket) => listener.add(socket)?,
+            Inherited::Unix(socket) => listener.add(socket)?,
+        }
+    }
+    let https_port = match https.first() {
+        Some(socket) => Some(socket.local_addr()?.port()),
+        None => None,
+    };
+    if let Some(acceptor) = acceptor {
+        for socket in https {
+            listener.add(TlsListener::new(socket, acceptor.clone())?)?;
+        }
+    }
+    Ok((listener, https_port))
+}
+
+/// Sends plain HTTP requests over to HTTPS on port in state.
+async fn redirect_to_https(req: Request<u16>) -> tide::Result {
+    let host = req.host().unwrap_or_default();
+    let path = match req.url().query() {
+        Some(query) => format!("{}?{}", req.url().path(), query),
+        None => String::from(req.url().path()),
+    };
+    Ok(match https::redirect_url(host, &path, *req.state()) {
+        Some(url) => tide::Redirect::permanent(url).into(),
+        None => tide::Response::builder(StatusCode::BadRequest)
+            .body("no valid Host header")
+            .build(),
+    })
+}
+
 #[async_std::main]
 async fn main() -> Result<(), std::io::Error> {
     femme::start();
-    let args = Args::parse();
-    let limiter = RateLimiter {
-        peers: Arc::new(DashMap::new()),
-        window: Duration::from_secs(10),
-        rps: 1,
+    let (args, links) = match load_args() {
+        Ok(loaded) => loaded,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                std::process::exit(2);
+            }
+        },
+    };
+    let settings = match Settings::new(&args, &links, None) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(2);
+        }
     };
 
-    limiter.start();
+    let known_hosts = match &args.known_hosts {
+        Some(path) => KnownHosts::open(path)?,
+        None => KnownHosts::in_memory(),
+    };
+    let tls = Arc::new(TlsConfig::new(known_hosts, args.opportunistic_tls));
+    let mut cache = Cache::new(Duration::from_secs(args.cache_ttl), args.cache_size)
+        .with_tls(tls.clone())
+        .with_options(fetch_options(&args, None));
+    if let Some(dir) = &args.cache_dir {
+        cache = cache
+            .with_store(DiskStore::open(dir, args.cache_dir_size)?)
+            .offline(args.offline);
+    }
+
+    let spool = match args.spool_size {
+        0 => None,
+        size => {
+            let dir = match &args.spool_dir {
+                Some(dir) => dir.into(),
+                None => std::env::temp_dir().join("proxy70-spool"),
+            };
+            // spooled files are not meant to outlive cached responses
+            let ttl = Duration::from_secs(args.cache_ttl);
+            Some(Arc::new(Spool::open(dir, size, ttl)?))
+        }
+    };
+
+    let certificates = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match Certificates::load(cert, key) {
+            Ok(certificates) => Some(Arc::new(certificates)),
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                std::process::exit(2);
+            }
+        },
+        _ => None,
+    };
+    if let Some(certificates) = &certificates {
+        watch_certificates(certificates.clone());
+    }
+
+    let state = State {
+        cache: Arc::new(cache),
+        tls,
+        spool,
+        settings: Arc::new(RwLock::new(Arc::new(settings))),
+        shutdown: Tracker::default(),
+        certificates,
+    };
+    evict_rate_limits(state.clone());
+    reload_on_hangup(state.clone(), args.clone())?;
 
-    let mut app = tide::new();
-    app.with(limiter);
-    app.with(tide::log::LogMiddleware::new());
+    let mut app = tide::with_state(state.clone());
+    app.with(InFlight);
+    app.with(AccessLog);
+    app.with(RequestMetrics);
+    app.with(RateLimiter);
 
-    app.at("/").get(root);
-    app.at("/robots.txt").serve_file("static/robots.txt")?;
-    app.at("/static").serve_dir("static/")?;
+    app.at("/").get(root).post(submit_ask);
+    app.at("/telnet").get(telnet_socket);
+    app.at("/metrics").get(render_metrics);
+    app.at("/healthz").get(healthz);
+    app.at("/readyz").get(readyz);
+    app.at("/robots.txt")
+        .serve_file(Path::new(&args.static_dir).join("robots.txt"))?;
+    app.at("/static").serve_dir(&args.static_dir)?;
 
-    app.listen(args.listen_addr).await?;
+    let (mut listener, https_port) = match listener(&args.listen, state.certificates.as_ref()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    listener.bind(app).await?;
+    for info in listener.info() {
+        log::info!("Server listening on {}", info);
+    }
+    match (&args.http_redirect, https_port) {
+        (Some(addr), Some(port)) => {
+            let mut redirect = tide::with_state(port);
+            redirect.at("/").all(redirect_to_https);
+            redirect.at("*").all(redirect_to_https);
+            let mut redirect = redirect.bind(addr.as_str()).await?;
+            for info in redirect.info() {
+                log::info!("Redirecting to HTTPS on {}", info);
+            }
+            task::spawn(async move { redirect.accept().await });
+        }
+        (Some(_), None) => log::warn!("there is no HTTPS listener to redirect to"),
+        (None, _) => {}
+    }
+    let mut signals = Signals::new([Signal::Term, Signal::Int])?;
+    // connections are accepted while draining too, so that probes see /readyz failing
+    let mut accepting = task::spawn(async move { listener.accept().await });
+    let failed = async { (&mut accepting).await.map(|()| None) };
+    let terminated = async { signals.next().await.transpose() };
+    let Some(signal) = failed.or(terminated).await? else {
+        return Ok(());
+    };
+    let grace = state.settings().shutdown_grace;
+    log::info!(
+        "got {:?}, waiting up to {:?} for {} requests to finish",
+        signal,
+        grace,
+        state.shutdown.active()
+    );
+    // another signal cuts waiting short
+    let impatient = async {
+        signals.next().await;
+        state.shutdown.active()
+    };
+    match state.shutdown.drain(grace).or(impatient).await {
+        0 => log::info!("all requests finished, exiting"),
+        left => log::warn!("exiting with {} requests unfinished", left),
+    }
+    accepting.cancel().await;
     Ok(())
 }
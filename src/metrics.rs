@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+/// Distinct upstream hosts counted separately, the rest are counted as "other",
+/// so that clients requesting random hosts can't grow metrics without bound.
+const MAX_HOSTS: usize = 100;
+/// Upper bounds of fetch latency buckets, seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Metrics of the running proxy, collected from wherever requests and fetches happen.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub struct Metrics {
+    pub requests: Counters,
+    pub fetch_latency: Histograms,
+    pub proxied_bytes: Counters,
+    pub cache: Counters,
+    pub rate_limited: Counters,
+    pub upstream_errors: Counters,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Counters::new(
+                "proxy70_http_requests_total",
+                "HTTP requests served, by route and status",
+                &["route", "status"],
+            ),
+            fetch_latency: Histograms::new(
+                "proxy70_upstream_fetch_duration_seconds",
+                "Time till gopher server starts responding, by item type",
+                &["type"],
+                LATENCY_BUCKETS,
+            ),
+            proxied_bytes: Counters::new(
+                "proxy70_proxied_bytes_total",
+                "Bytes received from gopher servers",
+                &[],
+            ),
+            cache: Counters::new(
+                "proxy70_cache_requests_total",
+                "Cache lookups, by result: hit, stale or miss",
+                &["result"],
+            ),
+            rate_limited: Counters::new(
+                "proxy70_rate_limited_total",
+                "Requests rejected by rate limit, by limit: client or upstream",
+                &["limit"],
+            ),
+            upstream_errors: Counters::new(
+                "proxy70_upstream_errors_total",
+                "Failed fetches from gopher servers, by host",
+                &["host"],
+            )
+            .capped(MAX_HOSTS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Renders metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.fetch_latency.render(&mut out);
+        self.proxied_bytes.render(&mut out);
+        self.cache.render(&mut out);
+        self.rate_limited.render(&mut out);
+        self.upstream_errors.render(&mut out);
+        out
+    }
+}
+
+/// Counters of a metric, one per combination of label values.
+pub struct Counters {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    /// Combinations of label values kept, once there are that many new ones become "other"
+    max_series: usize,
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counters {
+    pub fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            max_series: usize::MAX,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn capped(self, max_series: usize) -> Self {
+        Self { max_series, ..self }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[&str], n: u64) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        let mut labels: Vec<String> = labels.iter().map(|l| String::from(*l)).collect();
+        let mut values = self.values.lock().unwrap();
+        if values.len() >= self.max_series && !values.contains_key(&labels) {
+            labels = vec![String::from("other"); labels.len()];
+        }
+        *values.entry(labels).or_default() += n;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let values = self.values.lock().unwrap();
+        if values.is_empty() && self.labels.is_empty() {
+            let _ = writeln!(out, "{} 0", self.name);
+        }
+        for (values, n) in values.iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                label_set(self.labels, values, None),
+                n
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Histograms of a metric, one per combination of label values.
+pub struct Histograms {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    bounds: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+impl Histograms {
+    pub fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        bounds: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            bounds,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        let secs = duration.as_secs_f64();
+        let labels = labels.iter().map(|l| String::from(*l)).collect();
+        let mut values = self.values.lock().unwrap();
+        let histogram = values.entry(labels).or_default();
+        histogram.buckets.resize(self.bounds.len(), 0);
+        if let Some(i) = self.bounds.iter().position(|b| secs <= *b) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (values, histogram) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, n) in self.bounds.iter().zip(&histogram.buckets) {
+                cumulative += n;
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    label_set(self.labels, values, Some(&le)),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                label_set(self.labels, values, Some("+Inf")),
+                histogram.count
+            );
+            let labels = label_set(self.labels, values, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, histogram.count);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Formats labels like `{type="1",le="0.5"}`, empty string if there are none.
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    match pairs.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", pairs.join(",")),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendering() {
+        let metrics = Metrics::default();
+        metrics.requests.inc(&["/", "200"]);
+        metrics.requests.inc(&["/", "200"]);
+        metrics.upstream_errors.inc(&["evil\"host"]);
+        metrics
+            .fetch_latency
+            .observe(&["1"], Duration::from_millis(200));
+        metrics
+            .fetch_latency
+            .observe(&["1"], Duration::from_secs(60));
+        let text = metrics.render();
+        for line in [
+            "# TYPE proxy70_http_requests_total counter",
+            r#"proxy70_http_requests_total{route="/",status="200"} 2"#,
+            r#"proxy70_upstream_errors_total{host="evil\"host"} 1"#,
+            "proxy70_proxied_bytes_total 0",
+            r#"proxy70_upstream_fetch_duration_seconds_bucket{type="1",le="0.1"} 0"#,
+            r#"proxy70_upstream_fetch_duration_seconds_bucket{type="1",le="0.25"} 1"#,
+            r#"proxy70_upstream_fetch_duration_seconds_bucket{type="1",le="30"} 1"#,
+            r#"proxy70_upstream_fetch_duration_seconds_bucket{type="1",le="+Inf"} 2"#,
+            r#"proxy70_upstream_fetch_duration_seconds_count{type="1"} 2"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+
+        let hosts = Counters::new("hosts", "", &["host"]).capped(2);
+        for host in ["a", "b", "c", "a", "d"] {
+            hosts.inc(&[host]);
+        }
+        let mut text = String::new();
+        hosts.render(&mut text);
+        assert!(
+            text.contains("hosts{host=\"a\"} 2\nhosts{host=\"b\"} 1\nhosts{host=\"other\"} 2\n")
+        );
+    }
+}
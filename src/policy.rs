@@ -0,0 +1,248 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use async_std::io;
+use async_std::net::{TcpStream, ToSocketAddrs};
+
+/// Networks upstream servers may not be in unless explicitly allowed:
+/// this host, private, link-local, shared and reserved ranges, multicast,
+/// and IPv6 ones embedding IPv4 addresses, which could lead to blocked IPv4 hosts:
+/// IPv4-compatible `::/96` (including `::` and `::1`), NAT64 `64:ff9b::/96` and 6to4 `2002::/16`.
+/// IPv4-mapped addresses are checked as IPv4 ones.
+const DEFAULT_BLOCKED: &[Cidr] = &[
+    Cidr::v4(Ipv4Addr::new(0, 0, 0, 0), 8),
+    Cidr::v4(Ipv4Addr::new(10, 0, 0, 0), 8),
+    Cidr::v4(Ipv4Addr::new(100, 64, 0, 0), 10),
+    Cidr::v4(Ipv4Addr::new(127, 0, 0, 0), 8),
+    Cidr::v4(Ipv4Addr::new(169, 254, 0, 0), 16),
+    Cidr::v4(Ipv4Addr::new(172, 16, 0, 0), 12),
+    Cidr::v4(Ipv4Addr::new(192, 0, 0, 0), 24),
+    Cidr::v4(Ipv4Addr::new(192, 168, 0, 0), 16),
+    Cidr::v4(Ipv4Addr::new(198, 18, 0, 0), 15),
+    Cidr::v4(Ipv4Addr::new(224, 0, 0, 0), 4),
+    Cidr::v4(Ipv4Addr::new(240, 0, 0, 0), 4),
+    Cidr::v6(Ipv6Addr::UNSPECIFIED, 96),
+    Cidr::v6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96),
+    Cidr::v6(Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16),
+    Cidr::v6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    Cidr::v6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+    Cidr::v6(Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10),
+    Cidr::v6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),
+];
+
+/// Network in CIDR notation, e.g. `10.0.0.0/8` or `fc00::/7`.
+/// Plain address stands for network of that address alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    const fn v4(addr: Ipv4Addr, prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V4(addr),
+            prefix,
+        }
+    }
+
+    const fn v6(addr: Ipv6Addr, prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V6(addr),
+            prefix,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, width) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u32::from(net) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = width - self.prefix as u32;
+        net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr =
+            IpAddr::from_str(addr).map_err(|e| anyhow!("invalid address {:?}: {}", addr, e))?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|p| *p <= width),
+            None => Some(width),
+        };
+        let Some(prefix) = prefix else {
+            return Err(anyhow!("invalid prefix length in {:?}", s));
+        };
+        // IPv4-mapped network, e.g. `::ffff:10.0.0.0/104`, is matched as IPv4 one,
+        // with the 96 bits of mapping prefix taken off
+        let canonical = addr.to_canonical();
+        let prefix = match canonical.is_ipv4() && addr.is_ipv6() {
+            true if prefix < 96 => {
+                return Err(anyhow!(
+                    "prefix of IPv4-mapped network is too short in {:?}",
+                    s
+                ));
+            }
+            true => prefix - 96,
+            false => prefix,
+        };
+        Ok(Self {
+            addr: canonical,
+            prefix,
+        })
+    }
+}
+
+/// Connection refused because of [`Policy`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blocked {
+    pub host: String,
+    pub addr: SocketAddr,
+}
+
+impl std::fmt::Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host == self.addr.ip().to_string() {
+            write!(f, "connecting to {} is blocked by policy", self.addr)
+        } else {
+            write!(
+                f,
+                "connecting to {}:{} ({}) is blocked by policy",
+                self.host,
+                self.addr.port(),
+                self.addr.ip()
+            )
+        }
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// Addresses and ports of upstream servers proxy70 may connect to,
+/// so that public instance can't be used to reach internal services.
+///
+/// Explicitly listed network with longest prefix decides, deny list winning a tie.
+/// Addresses no listed network contains are allowed unless they are in ranges
+/// blocked by default, like loopback or private networks.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    /// When not empty, only these ports may be connected to
+    pub allow_ports: Vec<u16>,
+    pub deny_ports: Vec<u16>,
+}
+
+impl Policy {
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        let port = addr.port();
+        if self.deny_ports.contains(&port)
+            || !(self.allow_ports.is_empty() || self.allow_ports.contains(&port))
+        {
+            return false;
+        }
+        let ip = addr.ip();
+        let longest = |list: &[Cidr]| {
+            list.iter()
+                .filter(|c| c.contains(ip))
+                .map(|c| c.prefix)
+                .max()
+        };
+        match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => !DEFAULT_BLOCKED.iter().any(|c| c.contains(ip)),
+        }
+    }
+
+    /// Connects to the first allowed address host resolves to.
+    /// Checked address is connected to, so that host can't resolve differently in between.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, anyhow::Error> {
+        let mut blocked = None;
+        let mut last_error = None;
+        for addr in (host, port).to_socket_addrs().await? {
+            if !self.allows(addr) {
+                blocked.get_or_insert(Blocked {
+                    host: String::from(host),
+                    addr,
+                });
+                continue;
+            }
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match (last_error, blocked) {
+            (Some(e), _) => e.into(),
+            (None, Some(blocked)) => blocked.into(),
+            (None, None) => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not resolve to any address", host),
+            )
+            .into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(policy: &Policy, addr: &str) -> bool {
+        policy.allows(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn policy() {
+        let policy = Policy::default();
+        assert!(allows(&policy, "192.0.2.1:70"));
+        assert!(allows(&policy, "[2001:db8::1]:70"));
+        assert!(!allows(&policy, "127.0.0.1:6379"));
+        assert!(!allows(&policy, "169.254.169.254:80"));
+        assert!(!allows(&policy, "10.1.2.3:70"));
+        assert!(!allows(&policy, "[::1]:70"));
+        assert!(!allows(&policy, "[::ffff:192.168.1.1]:70"));
+        assert!(!allows(&policy, "[fd00::1]:70"));
+        assert!(!allows(&policy, "[64:ff9b::7f00:1]:70"));
+        assert!(!allows(&policy, "[::7f00:1]:70"));
+        assert!(!allows(&policy, "[::]:70"));
+        assert!(!allows(&policy, "[2002:7f00:1::]:70"));
+        assert!(allows(&policy, "[2003::1]:70"));
+
+        let policy = Policy {
+            allow: vec!["10.1.0.0/16".parse().unwrap()],
+            deny: vec!["10.1.2.3".parse().unwrap(), "192.0.2.0/24".parse().unwrap()],
+            allow_ports: vec![70, 105],
+            deny_ports: vec![],
+        };
+        assert!(allows(&policy, "10.1.0.1:70"));
+        assert!(!allows(&policy, "10.1.0.1:6379"));
+        assert!(!allows(&policy, "10.1.2.3:70"));
+        assert!(!allows(&policy, "10.2.0.1:70"));
+        assert!(!allows(&policy, "192.0.2.1:105"));
+        assert!(allows(&policy, "198.51.100.1:105"));
+
+        let mapped = "::ffff:10.0.0.0/104".parse::<Cidr>().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+        assert!(mapped.contains("10.1.2.3".parse().unwrap()));
+        assert!(!mapped.contains("11.1.2.3".parse().unwrap()));
+        assert!("::ffff:10.0.0.0/95".parse::<Cidr>().is_err());
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,69 @@
+/// Requested range lies past the end of the content.
+#[derive(Debug, PartialEq)]
+pub struct Unsatisfiable;
+
+/// Parses `Range` header for content of given length into inclusive byte range.
+/// Returns None for headers that are not understood, e.g. multiple ranges,
+/// in which case whole content should be served.
+pub fn parse(header: &str, len: u64) -> Option<Result<(u64, u64), Unsatisfiable>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // last N bytes
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(Err(Unsatisfiable));
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if start >= len {
+        return Some(Err(Unsatisfiable));
+    }
+    Some(Ok((start, end)))
+}
+
+/// Whether range may be served according to `If-Range` header, i.e. content has not changed
+/// since client got its entity tag. Only strong tags are compared, dates never match.
+pub fn if_range(header: Option<&str>, etag: Option<&str>) -> bool {
+    match header.map(str::trim) {
+        None => true,
+        Some(tag) => !tag.starts_with("W/") && Some(tag) == etag,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse("bytes=900-", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse("bytes=500-5000", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse("bytes=1000-", 1000), Some(Err(Unsatisfiable)));
+        assert_eq!(parse("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse("items=0-1", 1000), None);
+
+        assert!(if_range(None, None));
+        assert!(if_range(Some("\"abc\""), Some("\"abc\"")));
+        assert!(!if_range(Some("W/\"abc\""), Some("W/\"abc\"")));
+        assert!(!if_range(
+            Some("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some("\"abc\"")
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets kept per client or per server. Each bucket holds up to `burst` tokens
+/// and is refilled at `rate` tokens per second; every request takes one token.
+/// Zero rate turns limit off.
+pub struct RateLimit<K> {
+    buckets: DashMap<K, Bucket>,
+    rate: f64,
+    burst: f64,
+}
+
+impl<K: Eq + Hash> RateLimit<K> {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            buckets: DashMap::new(),
+            rate,
+            burst: burst as f64,
+        }
+    }
+
+    /// Takes token from bucket of `key`.
+    /// If bucket is empty, returns time until next token is there.
+    pub fn take(&self, key: K) -> Result<(), Duration> {
+        self.take_at(key, Instant::now())
+    }
+
+    fn take_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let mut bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Forgets buckets that are full again, they are no different from new ones.
+    pub fn evict(&self) {
+        self.evict_at(Instant::now());
+    }
+
+    fn evict_at(&self, now: Instant) {
+        if self.rate <= 0.0 {
+            return;
+        }
+        let refill = Duration::from_secs_f64(self.burst / self.rate);
+        self.buckets
+            .retain(|_, b| now.saturating_duration_since(b.updated) < refill);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+impl<K> std::fmt::Debug for RateLimit<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let limit = RateLimit::new(2.0, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limit.take_at("a", now), Ok(()));
+        }
+        assert_eq!(limit.take_at("a", now), Err(Duration::from_millis(500)));
+        assert_eq!(limit.take_at("b", now), Ok(()));
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limit.take_at("a", later), Ok(()));
+        assert!(limit.take_at("a", later).is_err());
+
+        limit.evict_at(now + Duration::from_millis(1600));
+        assert_eq!(limit.len(), 1);
+        limit.evict_at(now + Duration::from_secs(2));
+        assert!(limit.is_empty());
+
+        let unlimited = RateLimit::new(0.0, 1);
+        for _ in 0..10 {
+            assert_eq!(unlimited.take_at("a", now), Ok(()));
+        }
+    }
+}
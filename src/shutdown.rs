@@ -0,0 +1,117 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_std::io::{self, BufRead, Read};
+use async_std::task;
+
+/// How often draining checks whether requests are done.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keeps track of requests being served, so that shutdown can wait for them.
+/// Request is in flight until its response body is sent completely.
+#[derive(Clone, Default)]
+pub struct Tracker {
+    active: Arc<AtomicUsize>,
+    draining: Arc<AtomicBool>,
+}
+
+impl Tracker {
+    pub fn start(&self) -> Guard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Guard(self.active.clone())
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Shutdown has begun, proxy is no longer ready and closes connections after responses.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Waits for requests in flight to finish, for no longer than `grace`.
+    /// Returns number of requests left unfinished.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + grace;
+        while self.active() > 0 && Instant::now() < deadline {
+            task::sleep(POLL_INTERVAL.min(deadline - Instant::now())).await;
+        }
+        self.active()
+    }
+}
+
+/// Request in flight, finished when dropped.
+pub struct Guard(Arc<AtomicUsize>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Response body holding request in flight until it is dropped.
+pub struct GuardedBody<R> {
+    inner: R,
+    _guard: Guard,
+}
+
+impl<R> GuardedBody<R> {
+    pub fn new(inner: R, guard: Guard) -> Self {
+        Self {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl<R: Read + Unpin> Read for GuardedBody<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<R: BufRead + Unpin> BufRead for GuardedBody<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{Cursor, ReadExt};
+
+    #[async_std::test]
+    async fn draining() {
+        let tracker = Tracker::default();
+        let mut body = GuardedBody::new(Cursor::new(vec![0; 10]), tracker.start());
+        let plain = tracker.start();
+        assert_eq!(tracker.active(), 2);
+        drop(plain);
+
+        let mut data = Vec::new();
+        body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(tracker.drain(Duration::from_millis(50)).await, 1);
+        assert!(tracker.is_draining());
+
+        let finishing = task::spawn(async move {
+            task::sleep(Duration::from_millis(50)).await;
+            drop(body);
+        });
+        assert_eq!(tracker.drain(Duration::from_secs(5)).await, 0);
+        finishing.await;
+    }
+}
@@ -0,0 +1,124 @@
+use std::str::FromStr;
+
+use tide::http::Mime;
+
+/// Leading bytes of file and MIME types they identify.
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"{\\rtf", "application/rtf"),
+    (b"<?xml", "text/xml"),
+];
+
+/// File extensions and MIME types they usually have.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xml", "text/xml"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("rtf", "application/rtf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+];
+
+/// Guesses MIME type of file from its first bytes, falling back to file name extension.
+pub fn sniff(head: &[u8], filename: Option<&str>) -> Option<Mime> {
+    let by_magic = MAGIC
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+        .map(|(_, mime)| *mime)
+        .or_else(|| match head.get(..12)? {
+            // RIFF containers have format after chunk size
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E'] => Some("audio/wav"),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P'] => Some("image/webp"),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("video/mp4"),
+            _ => None,
+        });
+    let by_extension = || {
+        let (_, extension) = filename?.rsplit_once('.')?;
+        let extension = extension.to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(e, _)| *e == extension)
+            .map(|(_, mime)| *mime)
+    };
+    let mime = match by_magic {
+        // Ogg container holds both sounds and movies, only name can tell them apart
+        Some("audio/ogg") => by_extension()
+            .filter(|m| m.starts_with("video/"))
+            .or(by_magic),
+        _ => by_magic.or_else(by_extension),
+    };
+    Mime::from_str(mime?).ok()
+}
+
+/// Whether browsers are able to display such content by themselves.
+/// Documents able to run scripts, like HTML or SVG, are always downloaded instead,
+/// as they would run with proxy's origin.
+pub fn is_inline(mime: &Mime) -> bool {
+    let scriptable = mime.subtype() == "html" || mime.subtype().ends_with("xml");
+    !scriptable
+        && (matches!(mime.basetype(), "image" | "audio" | "video")
+            || matches!(mime.essence(), "text/plain" | "application/pdf"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing() {
+        let essence = |head: &[u8], name| sniff(head, name).map(|m| String::from(m.essence()));
+        assert_eq!(
+            essence(b"\x89PNG\r\n\x1a\n....", Some("pic.jpg")).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            essence(b"RIFF\x00\x00\x00\x00WAVEfmt ", None).as_deref(),
+            Some("audio/wav")
+        );
+        assert_eq!(
+            essence(b"just text", Some("README.TXT")).as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(essence(b"just text", Some("README")), None);
+        assert_eq!(
+            essence(b"OggS\x00\x02", Some("clip.ogv")).as_deref(),
+            Some("video/ogg")
+        );
+
+        assert!(is_inline(&sniff(b"OggS", None).unwrap()));
+        assert!(!is_inline(&sniff(b"PK\x03\x04", None).unwrap()));
+        assert!(!is_inline(&sniff(b"", Some("pic.svg")).unwrap()));
+        assert!(!is_inline(&sniff(b"", Some("index.html")).unwrap()));
+        assert!(!is_inline(&"application/xhtml+xml".parse().unwrap()));
+    }
+}
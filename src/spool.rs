@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::fs::File;
+use async_std::io::{self, BufReader, Read, ReadExt, WriteExt};
+use async_std::stream::Stream;
+use async_std::task;
+use sha2::{Digest, Sha256};
+use tide::log;
+
+use crate::cache::{Cache, CacheKey, Response};
+use crate::gopher::GopherURL;
+
+const EXTENSION: &str = "spool";
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunks passed directly a reader may lag behind
+const PASSED_CHUNKS: usize = 4;
+
+struct Entry {
+    path: PathBuf,
+    len: u64,
+    etag: String,
+    fetched: Instant,
+    used: Instant,
+}
+
+/// Download saved to spool completely, opened for reading.
+pub struct SpooledFile {
+    pub file: File,
+    pub len: u64,
+    /// Strong entity tag derived from file content
+    pub etag: String,
+}
+
+pub enum Spooled {
+    File(SpooledFile),
+    /// Download is still being written to spool, or does not go through it.
+    /// Its length is not known yet, so it can't be served in ranges.
+    Stream(Response),
+}
+
+/// Download being written to spool, read by all requests for it meanwhile.
+struct Download {
+    path: PathBuf,
+    progress: Mutex<Progress>,
+}
+
+#[derive(Default)]
+struct Progress {
+    /// Bytes written to file so far
+    written: u64,
+    /// Set once download outgrows spool, the rest of it is passed to readers directly
+    overflowed: bool,
+    /// Set once download is over, with error message if it failed
+    finished: Option<Result<(), String>>,
+    /// Readers waiting for more to be written
+    waiting: Vec<Sender<()>>,
+    /// Readers to pass the rest of download to once it overflows
+    passing: Vec<Sender<Arc<[u8]>>>,
+}
+
+impl Download {
+    /// Opens file for reading, following download till it is over.
+    /// Fails if download has overflowed, as what was not written can't be read anymore.
+    async fn follow(self: &Arc<Self>) -> io::Result<Response> {
+        let file = File::open(&self.path).await?;
+        let (wake, wakeup) = channel::bounded(1);
+        let (pass, passed) = channel::bounded(PASSED_CHUNKS);
+        {
+            let mut progress = self.progress.lock().unwrap();
+            if progress.overflowed {
+                return Err(io::Error::other("download does not fit into spool"));
+            }
+            progress.waiting.push(wake);
+            progress.passing.push(pass);
+        }
+        Ok(Box::new(BufReader::new(Follower {
+            file,
+            download: self.clone(),
+            read: 0,
+            wakeup,
+            passed,
+            chunk: Arc::from([]),
+            pos: 0,
+        })))
+    }
+
+    /// Changes progress and wakes readers up, returning how many of them are left.
+    fn update(&self, f: impl FnOnce(&mut Progress)) -> usize {
+        let mut progress = self.progress.lock().unwrap();
+        f(&mut progress);
+        // full channel means reader has a wakeup pending already
+        progress
+            .waiting
+            .retain(|w| !matches!(w.try_send(()), Err(TrySendError::Closed(_))));
+        progress.waiting.len()
+    }
+}
+
+/// Reads file being downloaded, waiting for writer whenever it catches up.
+struct Follower {
+    file: File,
+    download: Arc<Download>,
+    read: u64,
+    wakeup: Receiver<()>,
+    /// Chunks passed directly once download has overflowed
+    passed: Receiver<Arc<[u8]>>,
+    chunk: Arc<[u8]>,
+    pos: usize,
+}
+
+impl Read for Follower {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.chunk.len() {
+                let n = (this.chunk.len() - this.pos).min(buf.len());
+                buf[..n].copy_from_slice(&this.chunk[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            let (written, overflowed, finished) = {
+                let progress = this.download.progress.lock().unwrap();
+                (
+                    progress.written,
+                    progress.overflowed,
+                    progress.finished.clone(),
+                )
+            };
+            if this.read < written {
+                let max = (written - this.read).min(buf.len() as u64) as usize;
+                let n = ready!(Pin::new(&mut this.file).poll_read(cx, &mut buf[..max]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "spooled file is truncated",
+                    )));
+                }
+                this.read += n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            // the rest comes through channel, which is closed once download is over
+            if overflowed {
+                if let Some(chunk) = ready!(Pin::new(&mut this.passed).poll_next(cx)) {
+                    this.chunk = chunk;
+                    this.pos = 0;
+                    continue;
+                }
+            }
+            match finished {
+                Some(Ok(())) => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => {
+                    if ready!(Pin::new(&mut this.wakeup).poll_next(cx)).is_none() {
+                        return Poll::Ready(Err(io::Error::other("spooling stopped")));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<CacheKey, Entry>,
+    /// Total length of spooled files
+    size: u64,
+    /// Bytes written by downloads still in progress
+    in_flight: u64,
+}
+
+impl Index {
+    fn remove(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.size -= entry.len;
+        Some(entry)
+    }
+
+    /// Removes least recently used files until spool and downloads in progress fit
+    /// into `max_bytes`, returns false if that is not possible.
+    fn evict(&mut self, max_bytes: u64) -> bool {
+        while self.size + self.in_flight > max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.used)
+                .map(|(k, _)| k.clone())
+            else {
+                return false;
+            };
+            remove_file(self.remove(&oldest).unwrap().path);
+        }
+        true
+    }
+}
+
+/// Temporary files holding downloads, so that they can be served with known length
+/// and in ranges, e.g. to resume interrupted download.
+///
+/// Download is written to spool while it is sent to the client, and requests for it
+/// made meanwhile read the same file. Files are kept for `ttl`, or for as long as cache is
+/// offline, and total size of them,
+/// along with downloads in progress, is kept within `max_bytes` by removing least recently
+/// used ones. Download that does not fit is no longer written, the rest of it is passed
+/// to its readers directly. Spool is not persistent, files left by previous runs are
+/// removed on start.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    index: Mutex<Index>,
+    downloads: Mutex<HashMap<CacheKey, Arc<Download>>>,
+    next_id: AtomicU64,
+}
+
+impl Spool {
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64, ttl: Duration) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            max_bytes,
+            ttl,
+            index: Mutex::default(),
+            downloads: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns spooled file, or download being spooled, starting it through cache
+    /// if there is neither.
+    pub async fn fetch(
+        self: &Arc<Self>,
+        cache: &Arc<Cache>,
+        url: &GopherURL,
+    ) -> anyhow::Result<Spooled> {
+        let key = CacheKey::new(url, None);
+        if let Some(found) = self.get(&key, cache.is_offline()).await {
+            return Ok(Spooled::File(found));
+        }
+        let download = self.downloads.lock().unwrap().get(&key).cloned();
+        if let Some(download) = download {
+            match download.follow().await {
+                Ok(reader) => return Ok(Spooled::Stream(reader)),
+                // finished and evicted, or overflowed just now
+                Err(e) => log::debug!("failed to follow download of {}: {}", url, e),
+            }
+        }
+        let response = cache.fetch_stream(url, None, false).await?.data;
+        Ok(Spooled::Stream(self.start(key, response).await?))
+    }
+
+    /// Starts writing response to spool in background, returning reader of it.
+    async fn start(self: &Arc<Self>, key: CacheKey, response: Response) -> io::Result<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{}.{}", id, EXTENSION));
+        let file = File::create(&path).await?;
+        let download = Arc::new(Download {
+            path,
+            progress: Mutex::default(),
+        });
+        let reader = download.follow().await?;
+        {
+            let mut downloads = self.downloads.lock().unwrap();
+            // same download was started by concurrent request meanwhile
+            if downloads.contains_key(&key) {
+                drop(downloads);
+                remove_file(download.path.clone());
+                return Ok(response);
+            }
+            downloads.insert(key.clone(), download.clone());
+        }
+        task::spawn(self.clone().write(key, download, response, file));
+        Ok(reader)
+    }
+
+    /// Copies response into spool file, keeping the file if it is complete and fits.
+    async fn write(
+        self: Arc<Self>,
+        key: CacheKey,
+        download: Arc<Download>,
+        mut response: Response,
+        mut file: File,
+    ) {
+        let result = self.copy(&key, &download, &mut response, &mut file).await;
+        // upstream connection is not kept till readers are woken up
+        drop(response);
+        let path = download.path.clone();
+        let (written, overflowed) = {
+            let progress = download.progress.lock().unwrap();
+            (progress.written, progress.overflowed)
+        };
+        let finished = match result {
+            Ok(Some(etag)) => {
+                self.insert(
+                    key.clone(),
+                    Entry {
+                        path,
+                        len: written,
+                        etag,
+                        fetched: Instant::now(),
+                        used: Instant::now(),
+                    },
+                );
+                Ok(())
+            }
+            // file was removed already, readers keep it open till they are done
+            Ok(None) => Ok(()),
+            Err(e) => {
+                if !overflowed {
+                    self.release(written);
+                    remove_file(path);
+                }
+                Err(e.to_string())
+            }
+        };
+        self.forget(&key, &download);
+        download.update(|p| p.finished = Some(finished));
+    }
+
+    /// Copies response into file, returning its entity tag.
+    /// Returns None if download overflowed spool, the rest of it is passed to readers then.
+    /// Gives up once there are no readers left.
+    async fn copy(
+        &self,
+        key: &CacheKey,
+        download: &Arc<Download>,
+        response: &mut Response,
+        file: &mut File,
+    ) -> io::Result<Option<String>> {
+        let mut hasher = Sha256::new();
+        let mut len = 0;
+        let mut passing = None;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = response.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            if passing.is_none() && !self.reserve(n as u64) {
+                log::debug!("{} does not fit into spool", download.path.display());
+                self.release(len);
+                remove_file(download.path.clone());
+                // later requests fetch it on their own instead of waiting for it
+                self.forget(key, download);
+                let mut senders = Vec::new();
+                download.update(|p| {
+                    p.overflowed = true;
+                    senders = std::mem::take(&mut p.passing);
+                });
+                passing = Some(senders);
+            }
+            match passing.as_mut() {
+                None => {
+                    file.write_all(&buf[..n]).await?;
+                    file.flush().await?;
+                    hasher.update(&buf[..n]);
+                    len += n as u64;
+                    if download.update(|p| p.written = len) == 0 {
+                        return Err(io::Error::other("no readers left"));
+                    }
+                }
+                Some(senders) => {
+                    let chunk: Arc<[u8]> = Arc::from(&buf[..n]);
+                    let mut open = Vec::new();
+                    for sender in senders.drain(..) {
+                        if sender.send(chunk.clone()).await.is_ok() {
+                            open.push(sender);
+                        }
+                    }
+                    if open.is_empty() {
+                        return Err(io::Error::other("no readers left"));
+                    }
+                    *senders = open;
+                }
+            }
+        }
+        Ok(passing
+            .is_none()
+            .then(|| format!("\"{:x}\"", hasher.finalize())))
+    }
+
+    fn forget(&self, key: &CacheKey, download: &Arc<Download>) {
+        let mut downloads = self.downloads.lock().unwrap();
+        if downloads.get(key).is_some_and(|d| Arc::ptr_eq(d, download)) {
+            downloads.remove(key);
+        }
+    }
+
+    /// Makes room for `n` more bytes of download in progress.
+    fn reserve(&self, n: u64) -> bool {
+        let mut index = self.index.lock().unwrap();
+        index.in_flight += n;
+        if !index.evict(self.max_bytes) {
+            index.in_flight -= n;
+            return false;
+        }
+        true
+    }
+
+    fn release(&self, n: u64) {
+        self.index.lock().unwrap().in_flight -= n;
+    }
+
+    /// Returns spooled file, which does not expire while cache is `offline`.
+    async fn get(&self, key: &CacheKey, offline: bool) -> Option<SpooledFile> {
+        let (path, len, etag) = {
+            let mut index = self.index.lock().unwrap();
+            let entry = index.entries.get_mut(key)?;
+            if !offline && entry.fetched.elapsed() >= self.ttl {
+                remove_file(index.remove(key).unwrap().path);
+                return None;
+            }
+            entry.used = Instant::now();
+            (entry.path.clone(), entry.len, entry.etag.clone())
+        };
+        match File::open(&path).await {
+            Ok(file) => Some(SpooledFile { file, len, etag }),
+            Err(e) => {
+                log::warn!("spooled file {} is gone: {}", path.display(), e);
+                self.index.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    /// Keeps complete download, its bytes are no longer in flight.
+    fn insert(&self, key: CacheKey, entry: Entry) {
+        let mut index = self.index.lock().unwrap();
+        index.in_flight -= entry.len;
+        if let Some(old) = index.remove(&key) {
+            remove_file(old.path);
+        }
+        index.size += entry.len;
+        index.entries.insert(key, entry);
+    }
+}
+
+/// Files being read are removed too, their readers keep them open till the end.
+fn remove_file(path: PathBuf) {
+    if let Err(e) = std::fs::remove_file(&path) {
+        log::warn!("failed to remove spooled file {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(selector: &str) -> CacheKey {
+        let url = GopherURL::try_from(format!("example.com/9{}", selector).as_str()).unwrap();
+        CacheKey::new(&url, None)
+    }
+
+    /// Spools response completely, returning what was read while spooling.
+    async fn download(spool: &Arc<Spool>, selector: &str, len: usize) -> Vec<u8> {
+        let response: Response = Box::new(io::Cursor::new(vec![b'x'; len]));
+        let mut data = Vec::new();
+        let mut reader = spool.start(key(selector), response).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    fn open(name: &str, max_bytes: u64) -> (PathBuf, Arc<Spool>) {
+        let dir = std::env::temp_dir().join(format!("proxy70-{}-{}", name, std::process::id()));
+        let spool = Spool::open(&dir, max_bytes, Duration::from_secs(60)).unwrap();
+        (dir, Arc::new(spool))
+    }
+
+    #[async_std::test]
+    async fn eviction() {
+        let (dir, spool) = open("eviction", 20);
+        for selector in ["/a", "/b", "/c"] {
+            assert_eq!(download(&spool, selector, 10).await.len(), 10);
+            if selector == "/b" {
+                // "/a" becomes more recently used than "/b"
+                assert!(spool.get(&key("/a"), false).await.is_some());
+            }
+        }
+        assert!(spool.get(&key("/a"), false).await.is_some());
+        assert!(spool.get(&key("/b"), false).await.is_none());
+        let c = spool.get(&key("/c"), false).await.unwrap();
+        assert_eq!(c.etag, spool.get(&key("/a"), false).await.unwrap().etag);
+
+        // too big for the whole spool, still sent completely
+        assert_eq!(download(&spool, "/big", 21).await.len(), 21);
+        assert!(spool.get(&key("/big"), false).await.is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn sharing() {
+        let (dir, spool) = open("sharing", 100);
+        let (tx, body) = crate::body::channel();
+        let mut first = spool.start(key("/a"), Box::new(body)).await.unwrap();
+        tx.send(String::from("hello ")).await.unwrap();
+        // sent before download is over
+        let mut head = [0; 6];
+        first.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"hello ");
+
+        let download = spool.downloads.lock().unwrap().get(&key("/a")).cloned();
+        let mut second = download.unwrap().follow().await.unwrap();
+        tx.send(String::from("world")).await.unwrap();
+        drop(tx);
+        let (mut rest, mut whole) = (String::new(), String::new());
+        first.read_to_string(&mut rest).await.unwrap();
+        second.read_to_string(&mut whole).await.unwrap();
+        assert_eq!(rest, "world");
+        assert_eq!(whole, "hello world");
+        assert_eq!(spool.get(&key("/a"), false).await.unwrap().len, 11);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn overflow() {
+        let (dir, spool) = open("overflow", 10);
+        let (tx_a, body) = crate::body::channel();
+        let mut a = spool.start(key("/a"), Box::new(body)).await.unwrap();
+        let (tx_b, body) = crate::body::channel();
+        let mut b = spool.start(key("/b"), Box::new(body)).await.unwrap();
+        let b_download = spool.downloads.lock().unwrap()[&key("/b")].clone();
+        let mut head = [0; 6];
+        tx_a.send(String::from("aaaaaa")).await.unwrap();
+        a.read_exact(&mut head).await.unwrap();
+        // "/a" in flight leaves no room for "/b", which is passed through
+        tx_b.send(String::from("bbbbbb")).await.unwrap();
+        b.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"bbbbbb");
+        assert!(!b_download.path.exists());
+        assert!(b_download.follow().await.is_err());
+        assert_eq!(spool.index.lock().unwrap().in_flight, 6);
+
+        tx_b.send(String::from("bb")).await.unwrap();
+        drop((tx_a, tx_b));
+        let (mut rest_a, mut rest_b) = (String::new(), String::new());
+        a.read_to_string(&mut rest_a).await.unwrap();
+        b.read_to_string(&mut rest_b).await.unwrap();
+        assert_eq!(rest_b, "bb");
+        assert_eq!(spool.get(&key("/a"), false).await.unwrap().len, 6);
+        assert!(spool.get(&key("/b"), false).await.is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn cancel() {
+        let (dir, spool) = open("cancel", 100);
+        let (tx, body) = crate::body::channel();
+        let reader = spool.start(key("/a"), Box::new(body)).await.unwrap();
+        let download = spool.downloads.lock().unwrap()[&key("/a")].clone();
+        drop(reader);
+        tx.send(String::from("hello")).await.unwrap();
+        while download.progress.lock().unwrap().finished.is_none() {
+            task::sleep(Duration::from_millis(1)).await;
+        }
+        // nobody is left to read the rest, so it is not fetched
+        assert!(tx.send(String::from("world")).await.is_err());
+        assert!(!download.path.exists());
+        assert!(spool.downloads.lock().unwrap().is_empty());
+        assert_eq!(spool.index.lock().unwrap().in_flight, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+use tide::log;
+
+use crate::cache::CacheKey;
+use crate::gopher::{GopherItem, GopherURL};
+
+const INDEX_FILE: &str = "index";
+const OBJECTS_DIR: &str = "objects";
+/// Overwritten index lines tolerated before index is compacted.
+const COMPACT_SLACK: usize = 1024;
+
+/// Index record describing one stored response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub hash: String,
+    pub item_type: GopherItem,
+    pub fetched: SystemTime,
+    pub size: usize,
+}
+
+/// Stored response, along with order of its last use for eviction.
+struct Entry {
+    record: Record,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<CacheKey, Entry>,
+    /// Size of each stored object along with number of entries referring to it
+    objects: HashMap<String, (usize, usize)>,
+    /// Total size of stored objects
+    bytes: u64,
+    clock: u64,
+    /// Lines in index file, including overwritten ones
+    lines: usize,
+}
+
+impl Index {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Returns hash of object no entry refers to anymore, if any.
+    fn insert(&mut self, key: CacheKey, record: Record) -> Option<String> {
+        let object = self
+            .objects
+            .entry(record.hash.clone())
+            .or_insert((record.size, 0));
+        if object.1 == 0 {
+            self.bytes += record.size as u64;
+        }
+        object.1 += 1;
+        let used = self.tick();
+        let old = self.entries.insert(key, Entry { record, used })?;
+        self.release(&old.record)
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<String> {
+        let old = self.entries.remove(key)?;
+        self.release(&old.record)
+    }
+
+    fn release(&mut self, record: &Record) -> Option<String> {
+        let object = self.objects.get_mut(&record.hash)?;
+        object.1 -= 1;
+        if object.1 > 0 {
+            return None;
+        }
+        self.bytes -= object.0 as u64;
+        self.objects.remove(&record.hash);
+        Some(record.hash.clone())
+    }
+
+    /// Removes least recently used entries other than `keep` until objects fit into
+    /// `max_bytes`, returning hashes of objects to delete.
+    fn evict(&mut self, max_bytes: u64, keep: Option<&CacheKey>) -> Vec<String> {
+        let mut orphans = Vec::new();
+        while self.bytes > max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .filter(|(k, _)| Some(*k) != keep)
+                .min_by_key(|(_, e)| e.used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            orphans.extend(self.remove(&oldest));
+        }
+        orphans
+    }
+}
+
+/// Persistent content-addressed storage of gopher responses.
+///
+/// Response bodies are stored in `objects/` named by SHA-256 of their content,
+/// so identical responses are kept once. `index` is an append-only tab-separated
+/// log of `hash, fetch time, size, item type, URL, query` lines, last line for a given
+/// URL wins. Index is compacted when the store is opened and whenever it has grown
+/// much longer than the number of entries.
+///
+/// Objects are kept within `max_bytes` by evicting least recently used entries,
+/// and deleted once no entry refers to them.
+pub struct DiskStore {
+    root: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Index>,
+}
+
+impl DiskStore {
+    pub fn open(root: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(root.join(OBJECTS_DIR))?;
+        let mut records = HashMap::new();
+        match File::open(root.join(INDEX_FILE)) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    match parse_index_line(&line?) {
+                        Some((key, record)) => records.insert(key, record),
+                        None => continue,
+                    };
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let store = Self {
+            root,
+            max_bytes,
+            index: Mutex::new(Index::default()),
+        };
+        {
+            let mut index = store.index.lock().unwrap();
+            // oldest first, so that they are the first to be evicted
+            let mut records: Vec<_> = records.into_iter().collect();
+            records.sort_by_key(|(_, r)| r.fetched);
+            for (key, record) in records {
+                if store.object_path(&record.hash).exists() {
+                    index.insert(key, record);
+                }
+            }
+            index.evict(max_bytes, None);
+            store.compact(&mut index)?;
+        }
+        Ok(store)
+    }
+
+    /// Returns stored response along with the time it was fetched.
+    pub fn get(&self, key: &CacheKey) -> io::Result<Option<(Vec<u8>, SystemTime)>> {
+        let record = {
+            let mut index = self.index.lock().unwrap();
+            let used = index.tick();
+            let Some(entry) = index.entries.get_mut(key) else {
+                return Ok(None);
+            };
+            entry.used = used;
+            entry.record.clone()
+        };
+        match fs::read(self.object_path(&record.hash)) {
+            Ok(data) => Ok(Some((data, record.fetched))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warn!("object {} for {} is missing", record.hash, key.url);
+                self.index.lock().unwrap().remove(key);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn put(&self, key: &CacheKey, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 > self.max_bytes {
+            log::debug!("{} does not fit into cache directory", key.url);
+            return Ok(());
+        }
+        let record = Record {
+            hash: format!("{:x}", Sha256::digest(data)),
+            item_type: key.url.gopher_type,
+            fetched: SystemTime::now(),
+            size: data.len(),
+        };
+        // object is written under lock, so that eviction or compaction won't remove it
+        // before it is in the index
+        let mut index = self.index.lock().unwrap();
+        let path = self.object_path(&record.hash);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap())?;
+            // write to temporary file first so readers never see partial object
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, data)?;
+            fs::rename(&tmp, &path)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(INDEX_FILE))?
+            .write_all(format_index_line(key, &record).as_bytes())?;
+        index.lines += 1;
+        let mut orphans = Vec::from_iter(index.insert(key.clone(), record));
+        orphans.extend(index.evict(self.max_bytes, Some(key)));
+        for hash in orphans {
+            self.remove_object(&hash);
+        }
+        if index.lines > 2 * index.entries.len() + COMPACT_SLACK {
+            self.compact(&mut index)?;
+        }
+        Ok(())
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.root
+            .join(OBJECTS_DIR)
+            .join(&hash[..2])
+            .join(&hash[2..])
+    }
+
+    fn remove_object(&self, hash: &str) {
+        if let Err(e) = fs::remove_file(self.object_path(hash)) {
+            log::warn!("failed to remove object {}: {}", hash, e);
+        }
+    }
+
+    /// Rewrites index with current entries only, and deletes object files
+    /// no entry refers to, e.g. left by a crash.
+    fn compact(&self, index: &mut Index) -> io::Result<()> {
+        let tmp = self.root.join(Path::new(INDEX_FILE).with_extension("tmp"));
+        let mut f = io::BufWriter::new(File::create(&tmp)?);
+        for (key, entry) in index.entries.iter() {
+            f.write_all(format_index_line(key, &entry.record).as_bytes())?;
+        }
+        f.into_inner()?.sync_all()?;
+        fs::rename(tmp, self.root.join(INDEX_FILE))?;
+        index.lines = index.entries.len();
+
+        for dir in fs::read_dir(self.root.join(OBJECTS_DIR))? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let hash = format!("{}{}", prefix, file.file_name().to_string_lossy());
+                if !index.objects.contains_key(&hash) {
+                    fs::remove_file(file.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_index_line(key: &CacheKey, record: &Record) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\n",
+        record.hash,
+        record
+            .fetched
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        record.size,
+        record.item_type,
+        urlencoding::encode(&key.url.to_string()),
+        key.query
+            .as_deref()
+            .map(|q| urlencoding::encode(q).into_owned())
+            .unwrap_or_default(),
+    )
+}
+
+fn parse_index_line(line: &str) -> Option<(CacheKey, Record)> {
+    let mut f = line.split('\t');
+    let (hash, fetched, size, item_type, url, query) = (
+        f.next()?,
+        f.next()?,
+        f.next()?,
+        f.next()?,
+        f.next()?,
+        f.next()?,
+    );
+    if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let item_type = GopherItem::from(item_type.chars().next()?);
+    let mut url = GopherURL::try_from(urlencoding::decode(url).ok()?.as_ref()).ok()?;
+    // URL without selector does not keep item type, so restore it from the index
+    url.gopher_type = item_type;
+    let query = match query {
+        "" => None,
+        q => Some(urlencoding::decode(q).ok()?.into_owned()),
+    };
+    Some((
+        CacheKey { url, query },
+        Record {
+            hash: String::from(hash),
+            item_type,
+            fetched: UNIX_EPOCH + Duration::from_secs(fetched.parse().ok()?),
+            size: size.parse().ok()?,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_lines() {
+        let url = GopherURL::try_from("gopher://example.com:70/7/search me").unwrap();
+        let key = CacheKey::new(&url, Some("cats\tdogs"));
+        let record = Record {
+            hash: format!("{:x}", Sha256::digest(b"hello")),
+            item_type: GopherItem::FullTextSearch,
+            fetched: UNIX_EPOCH + Duration::from_secs(1700000000),
+            size: 5,
+        };
+        let line = format_index_line(&key, &record);
+        assert_eq!(line.matches('\t').count(), 5);
+        assert_eq!(
+            parse_index_line(line.trim_end()),
+            Some((key, record.clone()))
+        );
+        assert_eq!(parse_index_line("garbage\t1\t2"), None);
+    }
+
+    #[test]
+    fn persistence() {
+        let dir = std::env::temp_dir().join(format!("proxy70-store-{}", std::process::id()));
+        let url = GopherURL::try_from("example.com").unwrap();
+        let key = CacheKey::new(&url, None);
+        {
+            let store = DiskStore::open(&dir, 1024).unwrap();
+            assert_eq!(store.get(&key).unwrap(), None);
+            store.put(&key, b"old menu").unwrap();
+            store.put(&key, b"new menu").unwrap();
+        }
+        let store = DiskStore::open(&dir, 1024).unwrap();
+        let (data, _) = store.get(&key).unwrap().unwrap();
+        assert_eq!(data, b"new menu");
+        // replaced object is gone
+        assert_eq!(objects(&dir), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn objects(dir: &Path) -> usize {
+        fs::read_dir(dir.join(OBJECTS_DIR))
+            .unwrap()
+            .map(|d| fs::read_dir(d.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn eviction() {
+        let dir = std::env::temp_dir().join(format!("proxy70-evict-{}", std::process::id()));
+        let key = |selector: &str| {
+            let url = GopherURL::try_from(format!("example.com/0{}", selector).as_str());
+            CacheKey::new(&url.unwrap(), None)
+        };
+        {
+            let store = DiskStore::open(&dir, 20).unwrap();
+            store.put(&key("/a"), b"aaaaaaaaaa").unwrap();
+            store.put(&key("/b"), b"bbbbbbbbbb").unwrap();
+            // "/a" becomes more recently used than "/b"
+            assert!(store.get(&key("/a")).unwrap().is_some());
+            store.put(&key("/c"), b"cccccccccc").unwrap();
+            assert!(store.get(&key("/b")).unwrap().is_none());
+            assert!(store.get(&key("/a")).unwrap().is_some());
+            // identical content is stored and counted once
+            store.put(&key("/c2"), b"cccccccccc").unwrap();
+            assert!(store.get(&key("/a")).unwrap().is_some());
+            assert_eq!(objects(&dir), 2);
+            // too big for the whole store
+            store.put(&key("/big"), &[b'x'; 21]).unwrap();
+            assert!(store.get(&key("/big")).unwrap().is_none());
+
+            // stray object left by a crash
+            let stray = store.object_path(&format!("{:x}", Sha256::digest(b"stray")));
+            fs::create_dir_all(stray.parent().unwrap()).unwrap();
+            fs::write(stray, b"stray").unwrap();
+        }
+        // smaller budget on next start
+        let store = DiskStore::open(&dir, 10).unwrap();
+        assert_eq!(objects(&dir), 1);
+        assert_eq!(store.index.lock().unwrap().bytes, 10);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
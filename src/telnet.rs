@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+use async_std::io::{self, Read, ReadExt, Write, WriteExt};
+use async_std::net::{Shutdown, TcpStream};
+use async_std::sync::Mutex;
+use futures_lite::FutureExt;
+
+use crate::websocket;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TERMINAL_TYPE: u8 = 24;
+
+/// Terminal type reported to servers asking for it, BBSes use it to enable colours.
+const TERMINAL_NAME: &[u8] = b"ANSI";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum State {
+    #[default]
+    Data,
+    Iac,
+    Command(u8),
+    Sub,
+    SubIac,
+}
+
+/// Telnet client side option negotiation.
+///
+/// Lets server echo and suppress go-ahead, reports terminal type
+/// and refuses everything else.
+#[derive(Debug, Default)]
+pub struct Negotiator {
+    state: State,
+    sub: Vec<u8>,
+    /// Replies already sent, so that option negotiation won't loop
+    sent: HashSet<(u8, u8)>,
+}
+
+impl Negotiator {
+    /// Splits data received from server into terminal output and replies to be sent back.
+    pub fn receive(&mut self, input: &[u8], output: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &b in input {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, b) => {
+                    output.push(b);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    output.push(IAC);
+                    State::Data
+                }
+                (State::Iac, DO | DONT | WILL | WONT) => State::Command(b),
+                (State::Iac, SB) => {
+                    self.sub.clear();
+                    State::Sub
+                }
+                // go ahead, no-op and other commands without argument
+                (State::Iac, _) => State::Data,
+                (State::Command(command), option) => {
+                    self.reply(command, option, replies);
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, b) => {
+                    self.sub.push(b);
+                    State::Sub
+                }
+                (State::SubIac, SE) => {
+                    self.subnegotiate(replies);
+                    State::Data
+                }
+                (State::SubIac, b) => {
+                    self.sub.push(b);
+                    State::Sub
+                }
+            }
+        }
+    }
+
+    fn reply(&mut self, command: u8, option: u8, replies: &mut Vec<u8>) {
+        let answer = match (command, option) {
+            (WILL, ECHO | SUPPRESS_GO_AHEAD) => DO,
+            (WILL, _) => DONT,
+            (DO, SUPPRESS_GO_AHEAD | TERMINAL_TYPE) => WILL,
+            (DO, _) => WONT,
+            // disabling is always acknowledged by the side that asked
+            _ => return,
+        };
+        if self.sent.insert((answer, option)) {
+            replies.extend_from_slice(&[IAC, answer, option]);
+        }
+    }
+
+    fn subnegotiate(&mut self, replies: &mut Vec<u8>) {
+        // IAC SB TERMINAL-TYPE SEND IAC SE
+        if self.sub == [TERMINAL_TYPE, 1] {
+            replies.extend_from_slice(&[IAC, SB, TERMINAL_TYPE, 0]);
+            replies.extend_from_slice(TERMINAL_NAME);
+            replies.extend_from_slice(&[IAC, SE]);
+        }
+    }
+}
+
+/// Escapes user input to be sent to telnet server.
+pub fn escape(input: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(input.len());
+    for &b in input {
+        if b == IAC {
+            result.push(IAC);
+        }
+        result.push(b);
+    }
+    result
+}
+
+/// Relays telnet session to WebSocket client until either side disconnects.
+pub async fn relay(socket: impl Read + Write + Unpin, server: TcpStream) -> io::Result<()> {
+    let (mut socket_read, socket_write) = futures_lite::io::split(socket);
+    let socket_write = Mutex::new(socket_write);
+    let to_server = async {
+        let mut server = &server;
+        loop {
+            let frame = websocket::read_frame(&mut socket_read).await?;
+            match frame.opcode {
+                websocket::TEXT | websocket::BINARY | websocket::CONTINUATION => {
+                    server.write_all(&escape(&frame.payload)).await?
+                }
+                websocket::PING => {
+                    let mut socket = socket_write.lock().await;
+                    websocket::write_frame(&mut *socket, websocket::PONG, &frame.payload).await?
+                }
+                websocket::CLOSE => return Ok(()),
+                _ => {}
+            }
+        }
+    };
+    let to_client = async {
+        let mut server = &server;
+        let mut negotiator = Negotiator::default();
+        let mut buf = vec![0; 4096];
+        loop {
+            let n = server.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            let (mut output, mut replies) = (Vec::new(), Vec::new());
+            negotiator.receive(&buf[..n], &mut output, &mut replies);
+            if !replies.is_empty() {
+                server.write_all(&replies).await?;
+            }
+            if !output.is_empty() {
+                let mut socket = socket_write.lock().await;
+                websocket::write_frame(&mut *socket, websocket::BINARY, &output).await?;
+            }
+        }
+    };
+    let result = to_server.race(to_client).await;
+    let mut socket = socket_write.lock().await;
+    let close = websocket::close_payload(&result);
+    let _ = websocket::write_frame(&mut *socket, websocket::CLOSE, &close).await;
+    let _ = server.shutdown(Shutdown::Both);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let mut n = Negotiator::default();
+        let (mut output, mut replies) = (Vec::new(), Vec::new());
+        n.receive(
+            &[b'h', IAC, WILL, ECHO, IAC, DO, 31, b'i', IAC, IAC, IAC, DO],
+            &mut output,
+            &mut replies,
+        );
+        n.receive(
+            &[
+                TERMINAL_TYPE,
+                IAC,
+                SB,
+                TERMINAL_TYPE,
+                1,
+                IAC,
+                SE,
+                IAC,
+                WILL,
+                ECHO,
+            ],
+            &mut output,
+            &mut replies,
+        );
+        assert_eq!(output, [b'h', b'i', IAC]);
+        assert_eq!(
+            replies,
+            [
+                IAC,
+                DO,
+                ECHO,
+                IAC,
+                WONT,
+                31,
+                IAC,
+                WILL,
+                TERMINAL_TYPE,
+                IAC,
+                SB,
+                TERMINAL_TYPE,
+                0,
+                b'A',
+                b'N',
+                b'S',
+                b'I',
+                IAC,
+                SE
+            ]
+        );
+        assert_eq!(escape(&[1, IAC, 2]), [1, IAC, IAC, 2]);
+    }
+}
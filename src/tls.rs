@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write as _};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_std::io::{Read, Write};
+use async_std::net::TcpStream;
+use futures_rustls::client::TlsStream;
+use futures_rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use futures_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use futures_rustls::rustls::crypto::{self, ring, CryptoProvider};
+use futures_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, Error, SignatureScheme,
+};
+use futures_rustls::TlsConnector;
+use sha2::{Digest, Sha256};
+use tide::log;
+
+/// Certificate presented by server differs from the one pinned on first use.
+#[derive(Debug, Clone)]
+pub struct CertificateChanged {
+    pub host: String,
+    pub pinned: String,
+    pub presented: String,
+}
+
+impl std::fmt::Display for CertificateChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "certificate of {} has changed since first use (pinned {}, presented {}), \
+            remove it from known hosts if this is expected",
+            self.host, self.pinned, self.presented
+        )
+    }
+}
+
+impl std::error::Error for CertificateChanged {}
+
+/// Connection was closed without close_notify, so response may be cut short.
+#[derive(Debug)]
+pub struct Truncated;
+
+impl std::fmt::Display for Truncated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection closed without close_notify")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// Certificate fingerprints of TLS servers seen so far, i.e. trust on first use.
+///
+/// Optionally persisted to a file of `host:port fingerprint` lines.
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    hosts: Mutex<HashMap<String, String>>,
+}
+
+impl KnownHosts {
+    /// Pins are kept until process exits.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut hosts = HashMap::new();
+        match File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if let Some((host, fingerprint)) = line.trim().split_once(' ') {
+                        hosts.insert(String::from(host), String::from(fingerprint.trim()));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            path: Some(path),
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    /// Checks certificate fingerprint against pinned one, pinning it if host is seen first time.
+    fn check(&self, host: &str, fingerprint: &str) -> Result<(), CertificateChanged> {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get(host) {
+            Some(pinned) if pinned == fingerprint => Ok(()),
+            Some(pinned) => Err(CertificateChanged {
+                host: String::from(host),
+                pinned: pinned.clone(),
+                presented: String::from(fingerprint),
+            }),
+            None => {
+                log::info!("pinning certificate {} for {}", fingerprint, host);
+                if let Some(path) = &self.path {
+                    if let Err(e) = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut f| writeln!(f, "{} {}", host, fingerprint))
+                    {
+                        log::error!("failed to save known host {}: {}", host, e);
+                    }
+                }
+                hosts.insert(String::from(host), String::from(fingerprint));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for KnownHosts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KnownHosts({:?})", self.path)
+    }
+}
+
+/// Accepts any certificate with valid handshake signature,
+/// as long as it matches one seen before for this host.
+#[derive(Debug)]
+struct TofuVerifier {
+    host: String,
+    known_hosts: Arc<KnownHosts>,
+    provider: Arc<CryptoProvider>,
+    changed: Arc<Mutex<Option<CertificateChanged>>>,
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let fingerprint = format!("SHA256:{:x}", Sha256::digest(end_entity.as_ref()));
+        match self.known_hosts.check(&self.host, &fingerprint) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(e) => {
+                *self.changed.lock().unwrap() = Some(e);
+                Err(Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// TLS settings for upstream connections.
+pub struct TlsConfig {
+    known_hosts: Arc<KnownHosts>,
+    provider: Arc<CryptoProvider>,
+    /// Try TLS first for plain `gopher://` URLs, falling back to plain TCP.
+    pub opportunistic: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::new(KnownHosts::in_memory(), false)
+    }
+}
+
+impl TlsConfig {
+    pub fn new(known_hosts: KnownHosts, opportunistic: bool) -> Self {
+        Self {
+            known_hosts: Arc::new(known_hosts),
+            provider: Arc::new(ring::default_provider()),
+            opportunistic,
+        }
+    }
+
+    /// Performs TLS handshake over already established connection.
+    /// If pinned certificate does not match, returned error wraps [`CertificateChanged`].
+    pub async fn handshake(
+        &self,
+        stream: TcpStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<TlsConnection> {
+        let changed = Arc::new(Mutex::new(None));
+        let verifier = TofuVerifier {
+            host: format!("{}:{}", host, port),
+            known_hosts: self.known_hosts.clone(),
+            provider: self.provider.clone(),
+            changed: changed.clone(),
+        };
+        let config = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(String::from(host))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        match TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+        {
+            Ok(stream) => Ok(TlsConnection(stream)),
+            Err(e) => match changed.lock().unwrap().take() {
+                Some(changed) => Err(io::Error::new(io::ErrorKind::InvalidData, changed)),
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Checks whether connection failed because of pinned certificate mismatch.
+pub fn is_certificate_changed(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|e| e.downcast_ref::<CertificateChanged>().is_some())
+}
+
+/// Checks whether read failed because connection was closed without close_notify.
+pub fn is_truncated(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|e| e.downcast_ref::<Truncated>().is_some())
+}
+
+/// TLS stream that reports connection closed without close_notify as `Truncated` error,
+/// so that possibly incomplete responses are not cached.
+pub struct TlsConnection(TlsStream<TcpStream>);
+
+impl Read for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, Truncated)))
+            }
+            other => other,
+        }
+    }
+}
+
+/// Reader that treats `Truncated` error as normal EOF,
+/// since many small-net servers just drop connection after sending response.
+pub struct Lenient<R>(pub R);
+
+impl<R: Read + Unpin> Read for Lenient<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if is_truncated(&e) => Poll::Ready(Ok(0)),
+            other => other,
+        }
+    }
+}
+
+impl<R: Write + Unpin> Write for Lenient<R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+impl Write for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trust_on_first_use() {
+        let known_hosts = KnownHosts::in_memory();
+        assert!(known_hosts.check("example.com:70", "SHA256:aa").is_ok());
+        assert!(known_hosts.check("example.com:70", "SHA256:aa").is_ok());
+        assert!(known_hosts.check("example.com:7070", "SHA256:bb").is_ok());
+        let e = known_hosts
+            .check("example.com:70", "SHA256:bb")
+            .unwrap_err();
+        assert_eq!(e.pinned, "SHA256:aa");
+        assert_eq!(e.presented, "SHA256:bb");
+    }
+}
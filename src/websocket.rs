@@ -0,0 +1,186 @@
+use std::fmt;
+
+use async_std::io::{self, Read, ReadExt, Write, WriteExt};
+
+/// Messages larger than that are not expected from in-page terminal.
+const MAX_PAYLOAD: u64 = 64 * 1024;
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xa;
+
+/// Close status codes, see RFC 6455 section 7.4.1.
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Computes `Sec-WebSocket-Accept` header value for client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key.trim()).as_bytes(),
+    );
+    base64::encode(digest.as_ref())
+}
+
+/// Client broke the protocol, connection is to be closed with `code`.
+#[derive(Debug)]
+struct Violation {
+    code: u16,
+    reason: &'static str,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn violation(code: u16, reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Violation { code, reason })
+}
+
+/// Payload of close frame ending connection with `result`: status code and reason
+/// if client broke the protocol, empty otherwise.
+pub fn close_payload(result: &io::Result<()>) -> Vec<u8> {
+    let violation = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.get_ref())
+        .and_then(|e| e.downcast_ref::<Violation>());
+    match violation {
+        Some(v) => [&v.code.to_be_bytes()[..], v.reason.as_bytes()].concat(),
+        None => Vec::new(),
+    }
+}
+
+/// Tells whether `Origin` of handshake request is the page served from `host`,
+/// so that other sites can't open sessions on behalf of visitors.
+pub fn same_origin(origin: &str, host: &str) -> bool {
+    origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+        .is_some_and(|o| o.eq_ignore_ascii_case(host))
+}
+
+/// Reads single frame sent by client, unmasking its payload.
+/// Client frames must be masked, see RFC 6455 section 5.1.
+pub async fn read_frame(reader: &mut (impl Read + Unpin)) -> io::Result<Frame> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    if header[1] & 0x80 == 0 {
+        return Err(violation(PROTOCOL_ERROR, "client frame is not masked"));
+    }
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len).await?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len).await?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if len > MAX_PAYLOAD {
+        return Err(violation(MESSAGE_TOO_BIG, "websocket frame is too large"));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (n, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[n % 4];
+    }
+    Ok(Frame { opcode, payload })
+}
+
+/// Writes single unfragmented frame, server frames are never masked.
+pub async fn write_frame(
+    writer: &mut (impl Write + Unpin),
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+
+    #[test]
+    fn handshake_key() {
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[async_std::test]
+    async fn framing() {
+        // masked "Hello" from RFC 6455
+        let mut frame = Cursor::new(vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ]);
+        assert_eq!(
+            read_frame(&mut frame).await.unwrap(),
+            Frame {
+                opcode: TEXT,
+                payload: b"Hello".to_vec()
+            }
+        );
+
+        let mut out = Cursor::new(Vec::new());
+        write_frame(&mut out, BINARY, &[7; 300]).await.unwrap();
+        let out = out.into_inner();
+        assert_eq!(out[..4], [0x82, 126, 0x01, 0x2c]);
+        assert_eq!(out.len(), 304);
+
+        // same frame unmasked
+        let mut frame = Cursor::new(vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        let result = read_frame(&mut frame).await.map(|_| ());
+        assert_eq!(close_payload(&result)[..2], PROTOCOL_ERROR.to_be_bytes());
+        assert!(close_payload(&Ok(())).is_empty());
+    }
+
+    #[test]
+    fn origin() {
+        assert!(same_origin(
+            "https://proxy.example.org",
+            "proxy.example.org"
+        ));
+        assert!(same_origin("http://localhost:8080", "localhost:8080"));
+        assert!(!same_origin("http://localhost:8081", "localhost:8080"));
+        assert!(!same_origin("https://evil.org", "proxy.example.org"));
+        assert!(!same_origin("null", "proxy.example.org"));
+    }
+}
@@ -0,0 +1,216 @@
+// Minimal ANSI terminal for telnet sessions, expects ANSI_COLORS and TELNET_URL to be defined.
+(function () {
+    const COLS = 80;
+    const ROWS = 24;
+    const term = document.getElementById("terminal");
+    const decoder = new TextDecoder("utf-8");
+
+    let screen = [];
+    let row = 0;
+    let col = 0;
+    let style = { fg: null, bg: null, bold: false };
+    let escape = null;
+
+    function blankLine() {
+        return Array.from({ length: COLS }, () => ({ ch: " ", style: style }));
+    }
+
+    function clear() {
+        screen = Array.from({ length: ROWS }, blankLine);
+        row = 0;
+        col = 0;
+    }
+
+    function newline() {
+        row++;
+        if (row >= ROWS) {
+            screen.shift();
+            screen.push(blankLine());
+            row = ROWS - 1;
+        }
+    }
+
+    function put(ch) {
+        if (col >= COLS) {
+            col = 0;
+            newline();
+        }
+        screen[row][col] = { ch: ch, style: style };
+        col++;
+    }
+
+    function sgr(params) {
+        const codes = params.length ? params.split(";").map(Number) : [0];
+        const next = Object.assign({}, style);
+        for (let i = 0; i < codes.length; i++) {
+            const c = codes[i];
+            if (c === 0) {
+                next.fg = null;
+                next.bg = null;
+                next.bold = false;
+            } else if (c === 1) {
+                next.bold = true;
+            } else if (c === 22) {
+                next.bold = false;
+            } else if (c >= 30 && c <= 37) {
+                next.fg = c - 30;
+            } else if (c >= 90 && c <= 97) {
+                next.fg = c - 90 + 8;
+            } else if (c >= 40 && c <= 47) {
+                next.bg = c - 40;
+            } else if (c >= 100 && c <= 107) {
+                next.bg = c - 100 + 8;
+            } else if (c === 39) {
+                next.fg = null;
+            } else if (c === 49) {
+                next.bg = null;
+            } else if ((c === 38 || c === 48) && codes[i + 1] === 5) {
+                next[c === 38 ? "fg" : "bg"] = codes[i + 2];
+                i += 2;
+            }
+        }
+        style = next;
+    }
+
+    function csi(params, command) {
+        const args = params.split(";").map((p) => parseInt(p, 10) || 0);
+        const n = Math.max(args[0], 1);
+        switch (command) {
+            case "m": sgr(params); break;
+            case "A": row = Math.max(row - n, 0); break;
+            case "B": row = Math.min(row + n, ROWS - 1); break;
+            case "C": col = Math.min(col + n, COLS - 1); break;
+            case "D": col = Math.max(col - n, 0); break;
+            case "H":
+            case "f":
+                row = Math.min(Math.max(args[0], 1), ROWS) - 1;
+                col = Math.min(Math.max(args[1] || 1, 1), COLS) - 1;
+                break;
+            case "J":
+                if (args[0] === 2) {
+                    const [r, c] = [row, col];
+                    clear();
+                    [row, col] = [r, c];
+                }
+                break;
+            case "K":
+                for (let i = col; i < COLS; i++) {
+                    screen[row][i] = { ch: " ", style: style };
+                }
+                break;
+        }
+    }
+
+    function write(text) {
+        for (const ch of text) {
+            if (escape !== null) {
+                escape += ch;
+                if (escape === "[") {
+                    continue;
+                }
+                if (!escape.startsWith("[")) {
+                    escape = null;
+                } else if (/[@-~]/.test(ch)) {
+                    csi(escape.slice(1, -1), ch);
+                    escape = null;
+                }
+                continue;
+            }
+            switch (ch) {
+                case "\x1b": escape = ""; break;
+                case "\r": col = 0; break;
+                case "\n": newline(); break;
+                case "\b": col = Math.max(col - 1, 0); break;
+                case "\x07": break;
+                case "\0": break;
+                default: put(ch);
+            }
+        }
+    }
+
+    function css(s) {
+        const fg = s.fg === null ? null : ANSI_COLORS[s.bold && s.fg < 8 ? s.fg + 8 : s.fg];
+        const rules = [];
+        if (fg) rules.push("color:" + fg);
+        if (s.bg !== null) rules.push("background-color:" + ANSI_COLORS[s.bg]);
+        if (s.bold) rules.push("font-weight:bold");
+        return rules.join(";");
+    }
+
+    function escapeHtml(ch) {
+        return ch.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;");
+    }
+
+    let scheduled = false;
+    function render() {
+        scheduled = false;
+        let html = "";
+        screen.forEach((line, r) => {
+            let run = "";
+            let runStyle = null;
+            line.forEach((cell, c) => {
+                let ch = escapeHtml(cell.ch);
+                if (r === row && c === col) {
+                    ch = '<span class="cursor">' + ch + "</span>";
+                }
+                if (cell.style !== runStyle) {
+                    if (run) html += '<span style="' + css(runStyle) + '">' + run + "</span>";
+                    run = "";
+                    runStyle = cell.style;
+                }
+                run += ch;
+            });
+            html += '<span style="' + css(runStyle) + '">' + run + "</span>\n";
+        });
+        term.innerHTML = html;
+    }
+
+    function update() {
+        if (!scheduled) {
+            scheduled = true;
+            requestAnimationFrame(render);
+        }
+    }
+
+    clear();
+    const scheme = location.protocol === "https:" ? "wss://" : "ws://";
+    const socket = new WebSocket(scheme + location.host + TELNET_URL);
+    socket.binaryType = "arraybuffer";
+    socket.onmessage = (e) => {
+        write(decoder.decode(e.data, { stream: true }));
+        update();
+    };
+    socket.onclose = () => {
+        style = { fg: null, bg: null, bold: false };
+        write("\r\n[connection closed]");
+        update();
+    };
+
+    const keys = {
+        Enter: "\r\n",
+        Backspace: "\x7f",
+        Tab: "\t",
+        Escape: "\x1b",
+        ArrowUp: "\x1b[A",
+        ArrowDown: "\x1b[B",
+        ArrowRight: "\x1b[C",
+        ArrowLeft: "\x1b[D",
+    };
+    term.addEventListener("keydown", (e) => {
+        let data = keys[e.key];
+        if (e.ctrlKey && e.key.length === 1) {
+            data = String.fromCharCode(e.key.toUpperCase().charCodeAt(0) & 0x1f);
+        } else if (!data && e.key.length === 1 && !e.altKey && !e.metaKey) {
+            data = e.key;
+        }
+        if (data && socket.readyState === WebSocket.OPEN) {
+            socket.send(data);
+            e.preventDefault();
+        }
+    });
+    term.addEventListener("paste", (e) => {
+        socket.send(e.clipboardData.getData("text"));
+        e.preventDefault();
+    });
+    term.focus();
+})();